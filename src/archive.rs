@@ -0,0 +1,202 @@
+//! Packs compiled executables into tar archives and unpacks them for execution. Compression is
+//! handled by [`crate::executable`].
+//!
+//! Archives come back from clients when executing, so unpacking doesn't trust them: entries must
+//! stay inside the destination directory, only regular files, directories and relative symlinks
+//! are allowed, and the unpacked size is capped so that a small archive can't fill up /tmp.
+
+use std::{
+    fs,
+    io::Read,
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+use axum::http::StatusCode;
+use tar::{Archive, Builder, EntryType, HeaderMode};
+
+use crate::error::HTTPError;
+
+/// Limit on the total size of the files in an archive.
+const MAX_UNPACKED_BYTES: u64 = 256_000_000;
+
+/// Packs everything in `dir`, including hidden files, into a tar archive. Entries are added
+/// in sorted order with fixed timestamps and owners, so the same files always produce the same
+/// archive.
+pub fn pack(dir: &Path) -> Result<Vec<u8>> {
+    let mut builder = Builder::new(Vec::new());
+    builder.mode(HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+    append_dir(&mut builder, dir, Path::new(""))?;
+    Ok(builder.into_inner()?)
+}
+
+fn append_dir(builder: &mut Builder<Vec<u8>>, dir: &Path, archive_dir: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(dir.join(archive_dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let archive_path = archive_dir.join(entry.file_name());
+        let path = entry.path();
+        builder
+            .append_path_with_name(&path, &archive_path)
+            .with_context(|| format!("Failed to add {} to archive", archive_path.display()))?;
+        if entry.file_type()?.is_dir() {
+            append_dir(builder, dir, &archive_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn invalid_archive(message: String) -> anyhow::Error {
+    HTTPError(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid executable archive: {message}"),
+    )
+    .into()
+}
+
+/// Whether a path from an archive stays inside the directory it is relative to.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Unpacks a tar archive, read from a decompressor, into `dir`.
+pub fn unpack(archive: impl Read, dir: &Path) -> Result<()> {
+    // The tar headers also count towards the limit, which stops archives made of huge numbers of
+    // empty entries.
+    let mut archive = Archive::new(archive.take(MAX_UNPACKED_BYTES * 2));
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+
+    let mut unpacked_bytes: u64 = 0;
+    for entry in archive
+        .entries()
+        .context("Failed to read executable archive")?
+    {
+        let mut entry = entry.map_err(|err| invalid_archive(err.to_string()))?;
+        let path = entry
+            .path()
+            .map_err(|err| invalid_archive(err.to_string()))?
+            .into_owned();
+        if !is_contained(&path) {
+            return Err(invalid_archive(format!(
+                "{} is outside of the archive",
+                path.display()
+            )));
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {}
+            EntryType::Symlink => {
+                let target = entry
+                    .link_name()
+                    .map_err(|err| invalid_archive(err.to_string()))?
+                    .unwrap_or_default();
+                if !is_contained(&target) {
+                    return Err(invalid_archive(format!(
+                        "symlink {} points outside of the archive",
+                        path.display()
+                    )));
+                }
+            }
+            entry_type => {
+                return Err(invalid_archive(format!(
+                    "{} has unsupported type {entry_type:?}",
+                    path.display()
+                )))
+            }
+        }
+
+        unpacked_bytes += entry.size();
+        if unpacked_bytes > MAX_UNPACKED_BYTES {
+            return Err(invalid_archive(format!(
+                "more than {} MB when unpacked",
+                MAX_UNPACKED_BYTES / 1_000_000
+            )));
+        }
+        // unpack_in also refuses to write through symlinks that lead outside of `dir`.
+        entry
+            .unpack_in(dir)
+            .with_context(|| format!("Failed to unpack {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tar::Header;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// An archive with a single entry and no data. The name is written directly to the header,
+    /// since `tar::Builder` refuses to create the malicious paths being tested.
+    fn archive(name: &str, entry_type: EntryType, size: u64, link_name: &str) -> Vec<u8> {
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut data = header.as_bytes().to_vec();
+        data.extend([0; 1024]);
+        data
+    }
+
+    fn unpack_error(archive: &[u8]) -> String {
+        let dir = tempdir().unwrap();
+        unpack(archive, dir.path()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("lib")).unwrap();
+        fs::write(dir.path().join("lib/data.txt"), "data").unwrap();
+        symlink("lib/data.txt", dir.path().join("link")).unwrap();
+
+        let out_dir = tempdir().unwrap();
+        unpack(&pack(dir.path()).unwrap()[..], out_dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("link")).unwrap(),
+            "data"
+        );
+    }
+
+    #[test]
+    fn rejects_entries_outside_of_the_archive() {
+        let error = unpack_error(&archive("../evil", EntryType::Regular, 0, ""));
+        assert!(error.contains("outside of the archive"), "{error}");
+        let error = unpack_error(&archive("/tmp/evil", EntryType::Regular, 0, ""));
+        assert!(error.contains("outside of the archive"), "{error}");
+    }
+
+    #[test]
+    fn rejects_escaping_symlinks() {
+        for target in ["/etc/passwd", "../secret", "a/../../secret"] {
+            let error = unpack_error(&archive("link", EntryType::Symlink, 0, target));
+            assert!(error.contains("points outside of the archive"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_entry_types() {
+        let error = unpack_error(&archive("link", EntryType::Link, 0, "program"));
+        assert!(error.contains("unsupported type"), "{error}");
+    }
+
+    #[test]
+    fn caps_the_unpacked_size() {
+        let error = unpack_error(&archive(
+            "program",
+            EntryType::Regular,
+            MAX_UNPACKED_BYTES + 1,
+            "",
+        ));
+        assert!(error.contains("when unpacked"), "{error}");
+    }
+}
@@ -0,0 +1,451 @@
+//! `oj` runs the judge from the command line, with exactly the compile and execute logic the
+//! server uses, so that problem setters can validate solutions and test data locally.
+//!
+//! ```text
+//! oj run sol.cpp --lang cpp -i in.txt
+//! oj judge sol.py --tests dir/ --checker chk.cpp
+//! ```
+//!
+//! Tests are pairs of files like `1.in` and `1.out` (or `1.ans`). Without a checker, output is
+//! compared line by line, ignoring trailing whitespace. A checker is run testlib-style as
+//! `checker <input> <output> <answer>` and accepts the output by exiting with status 0.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand};
+use online_judge_rust::{
+    blob_store::{BlobStore, MemoryBlobStore},
+    compile::{compile, CompileRequest, CompileStatus},
+    executable::Executable,
+    execute::{run_executable, ExecuteOptions, ExecuteResponse, Verdict},
+    languages::{self, registry},
+    run_command::{run_command, CommandOptions, CommandSpec},
+    types::{Language, SourceFile},
+};
+use tempfile::tempdir;
+
+#[derive(Parser)]
+#[command(
+    name = "oj",
+    about = "Compile, run and judge programs like the online judge does"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compiles a program and runs it on one input.
+    Run {
+        source: PathBuf,
+        #[command(flatten)]
+        compile: CompileArgs,
+        /// File to use as stdin. Defaults to reading stdin.
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+        #[arg(short, long, default_value_t = 5000)]
+        timeout_ms: u32,
+    },
+    /// Compiles a program and judges it on every test in a directory.
+    Judge {
+        source: PathBuf,
+        #[command(flatten)]
+        compile: CompileArgs,
+        /// Directory of tests like `1.in` and `1.out`.
+        #[arg(long)]
+        tests: PathBuf,
+        /// Checker source, run as `checker <input> <output> <answer>`. A `testlib.h` next to it
+        /// is included when compiling it.
+        #[arg(long)]
+        checker: Option<PathBuf>,
+        #[arg(short, long, default_value_t = 5000)]
+        timeout_ms: u32,
+    },
+}
+
+#[derive(Args)]
+struct CompileArgs {
+    /// Language id, like "cpp", "java21" or "py12". Guessed from the file extension by default.
+    #[arg(short, long)]
+    lang: Option<String>,
+    /// Toolchain, like "gcc" or "clang". Defaults to the language's first toolchain.
+    #[arg(long)]
+    toolchain: Option<String>,
+    /// Compiler options. Defaults to the language's default options.
+    #[arg(long, allow_hyphen_values = true)]
+    options: Option<String>,
+}
+
+/// How long the checker may run on each test.
+const CHECKER_TIMEOUT_MS: u32 = 10_000;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = languages::init().and_then(|()| {
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let blob_store: Arc<dyn BlobStore> = Arc::new(MemoryBlobStore::default());
+            match cli.command {
+                Command::Run {
+                    source,
+                    compile,
+                    input,
+                    timeout_ms,
+                } => run(&source, &compile, input.as_deref(), timeout_ms, blob_store).await,
+                Command::Judge {
+                    source,
+                    compile,
+                    tests,
+                    checker,
+                    timeout_ms,
+                } => {
+                    judge(
+                        &source,
+                        &compile,
+                        &tests,
+                        checker.as_deref(),
+                        timeout_ms,
+                        blob_store,
+                    )
+                    .await
+                }
+            }
+        })
+    });
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn guess_language(source: &Path) -> Result<Language> {
+    let extension = source
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    registry()
+        .all()
+        .iter()
+        .find(|spec| spec.source_extensions.iter().any(|ext| ext == extension))
+        .map(|spec| spec.id.clone())
+        .ok_or_else(|| {
+            anyhow!(
+                "Can't tell the language of {} from its extension. Pass --lang.",
+                source.display()
+            )
+        })
+}
+
+/// Compiles `source`, printing compiler errors and warnings. Returns None if it didn't compile.
+async fn compile_file(
+    source: &Path,
+    args: &CompileArgs,
+    files: Vec<SourceFile>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<Option<Executable>> {
+    let language = match &args.lang {
+        Some(lang) => Language(lang.clone()),
+        None => guess_language(source)?,
+    };
+    let source_code = fs::read_to_string(source)
+        .with_context(|| format!("Failed to read {}", source.display()))?;
+    let response = compile(
+        CompileRequest {
+            source_code,
+            compiler_options: args.options.clone(),
+            language,
+            toolchain: args.toolchain.clone(),
+            files,
+            grader_id: None,
+            compile_timeout_ms: None,
+            compile_memory_limit_mb: None,
+            store_executable: false,
+        },
+        blob_store,
+    )
+    .await?;
+
+    eprint!("{}", response.compile_output.stderr);
+    if response.compile_status != CompileStatus::Success {
+        eprintln!(
+            "{}: compilation failed ({:?})",
+            source.display(),
+            response.compile_status
+        );
+        return Ok(None);
+    }
+    Ok(response.executable)
+}
+
+async fn execute_with_input(
+    executable: &Executable,
+    stdin: String,
+    timeout_ms: u32,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<ExecuteResponse> {
+    // The CLI only runs executables it has just compiled itself.
+    run_executable(
+        executable,
+        ExecuteOptions {
+            stdin: Some(stdin),
+            stdin_id: None,
+            timeout_ms,
+            file_io_name: None,
+        },
+        blob_store,
+        None,
+    )
+    .await
+}
+
+fn verdict_name(verdict: &Verdict) -> &'static str {
+    match verdict {
+        Verdict::Accepted => "accepted",
+        Verdict::WrongAnswer => "wrong answer",
+        Verdict::TimeLimitExceeded => "time limit exceeded",
+        Verdict::RuntimeError => "runtime error",
+    }
+}
+
+/// Prints why the program crashed, if it did.
+fn print_runtime_error(response: &ExecuteResponse) {
+    if let Some(reason) = &response.runtime_error_reason {
+        eprintln!("  {}", reason.explanation);
+    }
+    if let Some(exception) = &response.exception {
+        match (&exception.file, exception.line) {
+            (Some(file), Some(line)) => {
+                eprintln!("  {} at {file}:{line}", exception.exception_type)
+            }
+            _ => eprintln!("  {}", exception.exception_type),
+        }
+    }
+}
+
+async fn run(
+    source: &Path,
+    args: &CompileArgs,
+    input: Option<&Path>,
+    timeout_ms: u32,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<bool> {
+    let stdin = match input {
+        Some(input) => fs::read_to_string(input)
+            .with_context(|| format!("Failed to read {}", input.display()))?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let Some(executable) = compile_file(source, args, Vec::new(), blob_store.clone()).await? else {
+        return Ok(false);
+    };
+
+    let response = execute_with_input(&executable, stdin, timeout_ms, blob_store).await?;
+    print!("{}", response.stdout);
+    eprint!("{}", response.stderr);
+    eprintln!(
+        "{}  {}s  {} KB",
+        verdict_name(&response.verdict),
+        response.wall_time,
+        response.memory_usage
+    );
+    print_runtime_error(&response);
+    Ok(matches!(response.verdict, Verdict::Accepted))
+}
+
+struct Test {
+    name: String,
+    input: PathBuf,
+    answer: PathBuf,
+}
+
+/// Finds the tests in `dir`: every `X.in` with an `X.out` or `X.ans`, in numeric order when the
+/// names are numbers.
+fn find_tests(dir: &Path) -> Result<Vec<Test>> {
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let input = entry?.path();
+        if input.extension().is_none_or(|extension| extension != "in") {
+            continue;
+        }
+        let answer = ["out", "ans"]
+            .iter()
+            .map(|extension| input.with_extension(extension))
+            .find(|answer| answer.exists());
+        let Some(answer) = answer else {
+            eprintln!("Warning: {} has no .out or .ans file", input.display());
+            continue;
+        };
+        let name = input.file_stem().unwrap().to_string_lossy().into_owned();
+        tests.push(Test {
+            name,
+            input,
+            answer,
+        });
+    }
+    tests.sort_by_key(|test| {
+        (
+            test.name.parse::<u64>().unwrap_or(u64::MAX),
+            test.name.clone(),
+        )
+    });
+    if tests.is_empty() {
+        return Err(anyhow!("No tests found in {}", dir.display()));
+    }
+    Ok(tests)
+}
+
+/// Compares output line by line, ignoring trailing whitespace and trailing blank lines. Returns
+/// a description of the first difference, if any.
+fn diff(expected: &str, received: &str) -> Option<String> {
+    let lines = |text: &str| -> Vec<String> {
+        let mut lines: Vec<String> = text
+            .lines()
+            .map(|line| line.trim_end().to_owned())
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        lines
+    };
+    let expected = lines(expected);
+    let received = lines(received);
+    let line =
+        (0..expected.len().max(received.len())).find(|&i| expected.get(i) != received.get(i))?;
+    let show =
+        |line: Option<&String>| line.map_or("<end of output>".to_owned(), |line| line.clone());
+    Some(format!(
+        "line {}:\n    expected: {}\n    received: {}",
+        line + 1,
+        show(expected.get(line)),
+        show(received.get(line))
+    ))
+}
+
+/// Runs the checker on one test. Returns None if it accepts the output, or its message if it
+/// doesn't.
+fn check(checker: &Executable, test: &Test, output: &str) -> Result<Option<String>> {
+    let checker_dir = tempdir()?;
+    checker.unpack(checker_dir.path())?;
+    let output_dir = tempdir()?;
+    let output_path = output_dir.path().join("output.txt");
+    fs::write(&output_path, output)?;
+
+    let mut argv = checker.manifest.run_command.clone();
+    for path in [&test.input, &output_path, &test.answer] {
+        argv.push(fs::canonicalize(path)?.to_string_lossy().into_owned());
+    }
+    let command = CommandSpec::from_argv(&argv, checker_dir.path().to_owned())
+        .ok_or_else(|| anyhow!("Checker has an empty run command"))?;
+    let checker_output = run_command(
+        &command,
+        CommandOptions {
+            stdin: Bytes::new(),
+            timeout_ms: CHECKER_TIMEOUT_MS,
+            memory_limit_mb: None,
+        },
+    )?;
+
+    let message = checker_output.stderr.trim().to_owned();
+    if checker_output.timed_out {
+        return Err(anyhow!("Checker timed out on test {}", test.name));
+    }
+    match checker_output.exit_code {
+        0 => Ok(None),
+        // testlib's checkers exit with 3 when the checker itself fails, like when the answer
+        // file is invalid.
+        exit_code if exit_code == 3 << 8 => {
+            Err(anyhow!("Checker failed on test {}: {message}", test.name))
+        }
+        _ => Ok(Some(message)),
+    }
+}
+
+async fn judge(
+    source: &Path,
+    args: &CompileArgs,
+    tests_dir: &Path,
+    checker: Option<&Path>,
+    timeout_ms: u32,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<bool> {
+    let tests = find_tests(tests_dir)?;
+
+    let checker = match checker {
+        Some(checker) => {
+            let testlib = checker.with_file_name("testlib.h");
+            let files = match fs::read_to_string(&testlib) {
+                Ok(content) => vec![SourceFile {
+                    path: "testlib.h".to_owned(),
+                    content,
+                }],
+                Err(_) => Vec::new(),
+            };
+            let checker_args = CompileArgs {
+                lang: None,
+                toolchain: None,
+                options: None,
+            };
+            match compile_file(checker, &checker_args, files, blob_store.clone()).await? {
+                Some(executable) => Some(executable),
+                None => return Err(anyhow!("Failed to compile the checker")),
+            }
+        }
+        None => None,
+    };
+    let Some(executable) = compile_file(source, args, Vec::new(), blob_store.clone()).await? else {
+        return Ok(false);
+    };
+
+    let mut passed = 0;
+    for test in &tests {
+        let input = fs::read_to_string(&test.input)
+            .with_context(|| format!("Failed to read {}", test.input.display()))?;
+        let answer = fs::read_to_string(&test.answer)
+            .with_context(|| format!("Failed to read {}", test.answer.display()))?;
+        let mut response =
+            execute_with_input(&executable, input, timeout_ms, blob_store.clone()).await?;
+
+        let mut feedback = None;
+        if matches!(response.verdict, Verdict::Accepted) {
+            feedback = match &checker {
+                Some(checker) => check(checker, test, &response.stdout)?,
+                None => diff(&answer, &response.stdout),
+            };
+            if feedback.is_some() {
+                response.verdict = Verdict::WrongAnswer;
+            }
+        }
+
+        println!(
+            "test {:>4}: {:<20} {:>6}s {:>8} KB",
+            test.name,
+            verdict_name(&response.verdict),
+            response.wall_time,
+            response.memory_usage
+        );
+        match response.verdict {
+            Verdict::Accepted => passed += 1,
+            Verdict::WrongAnswer => {
+                if let Some(feedback) = feedback.filter(|feedback| !feedback.is_empty()) {
+                    println!("  {}", feedback);
+                }
+            }
+            Verdict::RuntimeError => print_runtime_error(&response),
+            Verdict::TimeLimitExceeded => {}
+        }
+    }
+
+    println!("passed {passed}/{} tests", tests.len());
+    Ok(passed == tests.len())
+}
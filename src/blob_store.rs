@@ -0,0 +1,249 @@
+//! Storage for inputs, outputs, graders, stored executables and the shared compile cache.
+//!
+//! Everything goes through the [`BlobStore`] trait, so that the judge can run without AWS. The
+//! backend is picked at startup from environment variables:
+//!
+//! - `STORAGE_BACKEND`: `s3` (the default), `local` or `memory`.
+//! - `STORAGE_BUCKET`: the S3 bucket. Defaults to "online-judge-rust-data".
+//! - `STORAGE_DIR`: the directory for the local backend. Defaults to "/tmp/online-judge-data".
+//! - `STORAGE_PREFIX`: prepended to every key, so that several deployments can share a bucket.
+//!
+//! Only S3 supports presigned URLs, which are used for large inputs and outputs.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{presigning::PresigningConfig, primitives::ByteStream};
+use bytes::Bytes;
+use uuid::Uuid;
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Returns None if nothing is stored at `key`.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    async fn put(&self, key: &str, data: Bytes, content_type: &str) -> Result<()>;
+
+    /// A URL that clients can upload `key` to with an HTTP PUT. None if the backend doesn't
+    /// support it.
+    async fn presigned_put_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>>;
+
+    /// A URL that clients can download `key` from. None if the backend doesn't support it.
+    async fn presigned_get_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>>;
+}
+
+/// Creates the blob store described by the `STORAGE_*` environment variables.
+pub async fn from_env() -> Result<Arc<dyn BlobStore>> {
+    let prefix = env::var("STORAGE_PREFIX").unwrap_or_default();
+    let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "s3".to_owned());
+    Ok(match backend.as_str() {
+        "s3" => {
+            let config = aws_config::load_from_env().await;
+            Arc::new(S3BlobStore {
+                client: aws_sdk_s3::Client::new(&config),
+                bucket: env::var("STORAGE_BUCKET")
+                    .unwrap_or_else(|_| "online-judge-rust-data".to_owned()),
+                prefix,
+            })
+        }
+        "local" => Arc::new(LocalBlobStore {
+            dir: env::var("STORAGE_DIR")
+                .unwrap_or_else(|_| "/tmp/online-judge-data".to_owned())
+                .into(),
+            prefix,
+        }),
+        "memory" => Arc::new(MemoryBlobStore::default()),
+        backend => return Err(anyhow!("Unknown STORAGE_BACKEND {backend}")),
+    })
+}
+
+pub struct S3BlobStore {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{key}", self.prefix))
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(object.body.collect().await?.into_bytes()))
+    }
+
+    async fn put(&self, key: &str, data: Bytes, content_type: &str) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{key}", self.prefix))
+            .body(ByteStream::from(data))
+            .content_type(content_type)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn presigned_put_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{key}", self.prefix))
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+        Ok(Some(request.uri().to_string()))
+    }
+
+    async fn presigned_get_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{key}", self.prefix))
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+        Ok(Some(request.uri().to_string()))
+    }
+}
+
+/// Stores blobs as files under a directory, for self-hosting without S3.
+pub struct LocalBlobStore {
+    dir: PathBuf,
+    prefix: String,
+}
+
+impl LocalBlobStore {
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let key = format!("{}{key}", self.prefix);
+        let is_contained = Path::new(&key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !is_contained {
+            return Err(anyhow!("Invalid blob key {key}"));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data.into())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Bytes, _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Write to a temporary file first so that a concurrent reader never sees a partial blob.
+        let tmp_path = path.with_file_name(format!(".tmp-{}", Uuid::new_v4()));
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    async fn presigned_put_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn presigned_get_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Keeps blobs in memory, for tests and local development. Nothing is persisted.
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: Mutex<HashMap<String, Bytes>>,
+}
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self.blobs.lock().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, data: Bytes, _content_type: &str) -> Result<()> {
+        self.blobs.lock().unwrap().insert(key.to_owned(), data);
+        Ok(())
+    }
+
+    async fn presigned_put_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn presigned_get_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_store(prefix: &str) -> LocalBlobStore {
+        LocalBlobStore {
+            dir: "/data".into(),
+            prefix: prefix.to_owned(),
+        }
+    }
+
+    #[test]
+    fn local_paths_apply_the_prefix() {
+        let store = local_store("staging/");
+        assert_eq!(
+            store.path("executables/a.json").unwrap(),
+            Path::new("/data/staging/executables/a.json")
+        );
+    }
+
+    #[test]
+    fn local_paths_stay_in_the_directory() {
+        let store = local_store("");
+        for key in ["../etc/passwd", "inputs/../../x", "/etc/passwd"] {
+            assert!(store.path(key).is_err(), "{key}");
+        }
+        // The prefix is part of the key, so it can't be used to escape either.
+        assert!(local_store("../").path("x").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_round_trip() {
+        let store = MemoryBlobStore::default();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        store
+            .put("a", Bytes::from_static(b"data"), "text/plain")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("a").await.unwrap(),
+            Some(Bytes::from_static(b"data"))
+        );
+    }
+}
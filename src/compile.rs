@@ -3,197 +3,551 @@ use std::{
     io::Write,
     os::unix::process::ExitStatusExt,
     path::{Path, PathBuf},
-    process::{Command, ExitStatus},
+    process::ExitStatus,
+    sync::Arc,
 };
 
-use anyhow::{anyhow, Context, Result};
-use axum::Json;
-use base64::{prelude::BASE64_STANDARD, Engine};
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, Json};
 use bytes::Bytes;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use tempfile::tempdir;
 
 use crate::{
-    error::AppError,
-    run_command::{run_command, CommandOptions, CommandOutput},
-    types::{Executable, Language},
+    blob_store::BlobStore,
+    compile_cache::{self, CacheEntry},
+    diagnostics::{self, Diagnostic, DiagnosticsFormat},
+    error::{AppError, HTTPError},
+    executable::{self, Executable, Manifest},
+    grader::load_grader,
+    java,
+    languages::{fill_argv, registry, SourceFilenameRule},
+    pch::{precompiled_header, PrecompiledHeader},
+    run_blocking,
+    run_command::{run_command, CommandOptions, CommandOutput, CommandSpec},
+    types::{Language, SourceFile},
+    AppState,
 };
 
 #[derive(Deserialize)]
 pub struct CompileRequest {
     pub source_code: String,
-    pub compiler_options: String,
+    /// Defaults to the language's `default_compiler_options`.
+    pub compiler_options: Option<String>,
     pub language: Language,
+    /// Which compiler to use, such as "gcc" or "clang" for C++. Defaults to the language's first
+    /// toolchain.
+    pub toolchain: Option<String>,
+    /// Additional files to compile or make available alongside `source_code`, such as local
+    /// headers, other Java classes or Python modules.
+    #[serde(default)]
+    pub files: Vec<SourceFile>,
+    /// Links the program against a problem-provided grader; see [`crate::grader`].
+    pub grader_id: Option<String>,
+    /// Defaults to 20 seconds. At most [`MAX_COMPILE_TIMEOUT_MS`].
+    pub compile_timeout_ms: Option<u32>,
+    /// Address space limit for the compiler. Defaults to no limit. Note that the JVM reserves a
+    /// lot of address space up front, so javac needs a generous limit.
+    pub compile_memory_limit_mb: Option<u32>,
+    /// Store the executable on the server and return its `executable_id`, which can be passed to
+    /// `/execute` instead of the whole executable. The executable itself is then left out of the
+    /// response.
+    #[serde(default)]
+    pub store_executable: bool,
+}
+
+const DEFAULT_COMPILE_TIMEOUT_MS: u32 = 20_000;
+const MAX_COMPILE_TIMEOUT_MS: u32 = 60_000;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompileStatus {
+    Success,
+    /// The program failed to compile.
+    Error,
+    /// The compiler ran past `compile_timeout_ms`.
+    Timeout,
+    /// The compiler crashed, such as an internal compiler error, so the program may be fine.
+    Internal,
+}
+
+impl CompileStatus {
+    fn from_output(compile_output: &CommandOutput) -> CompileStatus {
+        let status = ExitStatus::from_raw(compile_output.exit_code);
+        if compile_output.timed_out {
+            CompileStatus::Timeout
+        } else if status.success() {
+            CompileStatus::Success
+        } else if status.signal().is_some()
+            // The compiler is run directly, so it being killed shows up as a signal, but GCC and
+            // Clang report their own subprocesses crashing as internal errors.
+            || compile_output.stderr.contains("internal compiler error")
+            || compile_output.stderr.contains("PLEASE submit a bug report")
+        {
+            CompileStatus::Internal
+        } else {
+            CompileStatus::Error
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub struct CompileResponse {
-    /// None if the compilation did not succeed.
+    pub compile_status: CompileStatus,
+
+    /// None if the compilation did not succeed, or if `/compile` stored it and returned its
+    /// `executable_id` instead.
     pub executable: Option<Executable>,
 
+    /// Id of the stored executable, if `store_executable` was set.
+    pub executable_id: Option<String>,
+
     /// Process output of the compilation command.
     pub compile_output: CommandOutput,
+
+    /// Errors and warnings parsed from `compile_output`.
+    pub diagnostics: Vec<Diagnostic>,
+
+    /// Whether the executable came from the compile cache instead of being compiled.
+    pub cache_hit: bool,
 }
 
-/// Precompile bits/stdc++.h.
-///
-/// Building bits/stdc++.h can be very slow. We can substantially speed this up by precompiling
-/// headers: https://gcc.gnu.org/onlinedocs/gcc/Precompiled-Headers.html
-///
-/// However, precompiling headers is slow (~6s for C++23), and /tmp storage space is expensive, so
-/// we only precompile bits/stdc++.h for the default C++ compiler option.
-///
-/// I believe flags like -Wall are ignored, but flags like -std, -O2, and -fsanitize=address must
-/// match the flags used to precompile the header.
+/// Checks that a submitted file path stays inside the source directory.
 ///
-/// We don't do this precompilation in the dockerfile because lambda disk read speeds are abysmally
-/// slow (~6 MB/s empirically), and the precompiled headers are quite large.
-fn precompile_headers(compile_request: &CompileRequest) -> Result<()> {
-    let cpp_version = "23";
-
-    if compile_request.language != Language::Cpp
-        || !compile_request.compiler_options.contains("-O2")
-        || !compile_request
-            .compiler_options
-            .contains(&format!("-std=c++{cpp_version}"))
-        || !compile_request
-            .source_code
-            .contains("#include <bits/stdc++.h>")
-    {
-        return Ok(());
+/// Paths are restricted to a conservative set of characters since they are passed to the
+/// compiler, and can't start with '-' so that they aren't mistaken for options.
+fn validate_source_path(path: &str) -> Result<()> {
+    let is_valid = !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/'))
+        && path.split('/').all(|component| {
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && !component.starts_with('-')
+        });
+    if !is_valid {
+        return Err(HTTPError(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Invalid file path {path:?}. Paths must be relative, can't contain \"..\" or \
+                 start with '-', and may only contain letters, digits, '_', '-', '.' and '/'."
+            ),
+        )
+        .into());
     }
+    Ok(())
+}
 
-    let precompiled_header_path =
-        format!("/tmp/precompiled-headers/bits/stdc++.h.gch/{cpp_version}");
-
-    if Path::new(&precompiled_header_path).exists() {
-        return Ok(());
+/// A failed compilation for problems found before running the compiler.
+fn compile_error(message: String) -> CompileResponse {
+    CompileResponse {
+        compile_status: CompileStatus::Error,
+        executable: None,
+        executable_id: None,
+        compile_output: CommandOutput {
+            stdout: String::new(),
+            stderr: message,
+            wall_time: "0.00".to_owned(),
+            memory_usage: "0".to_owned(),
+            // Wait status for exit status 1
+            exit_code: 1 << 8,
+            exit_signal: None,
+            timed_out: false,
+        },
+        diagnostics: Vec::new(),
+        cache_hit: false,
     }
+}
 
-    // todo: disable in local development
-    if !Command::new("g++")
-        .arg("-o")
-        .arg(precompiled_header_path)
-        .arg(format!("-std=c++{cpp_version}"))
-        .arg("-O2")
-        .arg("/usr/include/c++/11/x86_64-amazon-linux/bits/stdc++.h")
-        .status()
-        .with_context(|| format!("Failed to precompile header"))?
-        .success()
+pub async fn compile(
+    compile_request: CompileRequest,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<CompileResponse> {
+    let spec = registry().get(&compile_request.language)?;
+    let grader = match &compile_request.grader_id {
+        Some(grader_id) => {
+            if !spec.supports_graders {
+                return Err(HTTPError(
+                    StatusCode::BAD_REQUEST,
+                    format!("Graders are not supported for {}", spec.id.0),
+                )
+                .into());
+            }
+            let grader = load_grader(blob_store.as_ref(), grader_id).await?;
+            if grader.language != spec.id {
+                return Err(HTTPError(
+                    StatusCode::BAD_REQUEST,
+                    format!("Grader {grader_id} is for {}", grader.language.0),
+                )
+                .into());
+            }
+            Some(grader)
+        }
+        None => None,
+    };
+    let compiler_options = compile_request
+        .compiler_options
+        .unwrap_or_else(|| spec.default_compiler_options.clone());
+    let compiler_args = spec.parse_compiler_options(&compiler_options)?;
+    // The options also apply to the grader's sources, where a macro like -Dunsetenv=puts could
+    // change what the grader does.
+    if grader.is_some()
+        && compiler_args
+            .iter()
+            .any(|arg| arg.starts_with("-D") || arg.starts_with("-U"))
     {
-        return Err(anyhow!(
-            "Command to precompile header exited with nonzero exit code"
-        ));
+        return Err(HTTPError(
+            StatusCode::BAD_REQUEST,
+            "-D and -U can't be used with a grader".to_owned(),
+        )
+        .into());
     }
+    // Options that parse to the same arguments share cache entries.
+    let compiler_options = shell_words::join(&compiler_args);
+    let compile_timeout_ms = compile_request
+        .compile_timeout_ms
+        .unwrap_or(DEFAULT_COMPILE_TIMEOUT_MS);
+    if compile_timeout_ms == 0 || compile_timeout_ms > MAX_COMPILE_TIMEOUT_MS {
+        return Err(HTTPError(
+            StatusCode::BAD_REQUEST,
+            format!("compile_timeout_ms must be between 1 and {MAX_COMPILE_TIMEOUT_MS}"),
+        )
+        .into());
+    }
+    if compile_request.compile_memory_limit_mb == Some(0) {
+        return Err(HTTPError(
+            StatusCode::BAD_REQUEST,
+            "compile_memory_limit_mb must be positive".to_owned(),
+        )
+        .into());
+    }
+    let toolchain = spec.toolchain(compile_request.toolchain.as_deref())?;
 
-    Ok(())
-}
-
-pub fn compile(compile_request: CompileRequest) -> Result<CompileResponse> {
     let tmp_dir = tempdir()?;
     let tmp_out_dir = tempdir()?;
 
-    let program_filename: PathBuf = match compile_request.language {
-        Language::Cpp => "program.cpp".into(),
-        Language::Java21 => {
-            let re = Regex::new(r"public\s+class\s+(\w+)").unwrap();
-            if let Some(captures) = re.captures(&compile_request.source_code) {
-                format!("{}.java", &captures[1]).into()
-            } else {
-                "Main.java".into() // fallback, something went wrong
+    let (program_filename, main_class): (PathBuf, String) = match &spec.source_filename {
+        SourceFilenameRule::Fixed(filename) => {
+            let filename = PathBuf::from(filename);
+            let main_class = filename.file_stem().unwrap().to_str().unwrap().to_owned();
+            (filename, main_class)
+        }
+        SourceFilenameRule::JavaMainClass => {
+            match java::find_entry_point(&compile_request.source_code) {
+                Ok(entry_point) => (entry_point.source_path.into(), entry_point.main_class),
+                Err(message) => return Ok(compile_error(message)),
             }
         }
-        Language::Py12 => "program.py".into(),
     };
 
-    let mut source_file = File::create(tmp_dir.path().join(&program_filename))?;
-    source_file.write_all(compile_request.source_code.as_bytes())?;
-    drop(source_file);
-
-    if let Err(err) = precompile_headers(&compile_request) {
-        println!("Warning: Failed to precompile headers: {err}");
+    let source = program_filename.to_str().unwrap();
+    // The Java source path comes from the package declaration in the user's code.
+    validate_source_path(source)?;
+    let mut files = vec![SourceFile {
+        path: source.to_owned(),
+        content: compile_request.source_code,
+    }];
+    for file in compile_request.files {
+        validate_source_path(&file.path)?;
+        if files.iter().any(|other| other.path == file.path) {
+            return Err(HTTPError(
+                StatusCode::BAD_REQUEST,
+                format!("Duplicate file path {}", file.path),
+            )
+            .into());
+        }
+        files.push(file);
     }
+    let source_files = files.iter().map(|file| file.path.clone()).collect();
+    // Grader files are trusted, so they are validated by whoever stores the grader.
+    let grader_files = grader.map(|grader| grader.files).unwrap_or_default();
 
-    let command = match compile_request.language {
-        Language::Cpp => format!(
-            "g++ -I/tmp/precompiled-headers -o {} {} {}",
-            tmp_out_dir
-                .path()
-                .join(program_filename.clone().with_extension(""))
-                .as_os_str()
-                .to_str()
-                .unwrap(),
-            compile_request.compiler_options,
-            program_filename.as_os_str().to_str().unwrap(),
-        ),
-        Language::Java21 => format!(
-            "javac -d {} {} {}",
-            tmp_out_dir.path().as_os_str().to_str().unwrap(),
-            compile_request.compiler_options,
-            program_filename.as_os_str().to_str().unwrap(),
-        ),
-        Language::Py12 => format!(
-            "cp {} {}",
-            tmp_dir
-                .path()
-                .join(&program_filename)
-                .as_os_str()
-                .to_str()
-                .unwrap(),
-            tmp_out_dir
-                .path()
-                .join(&program_filename)
-                .as_os_str()
-                .to_str()
-                .unwrap(),
+    let version = toolchain.map_or_else(|| spec.version(), |toolchain| toolchain.version());
+    let cache_key = compile_cache::cache_key(
+        [
+            spec.id.0.as_str(),
+            toolchain.map_or("", |toolchain| &toolchain.id),
+            version.unwrap_or(""),
+            &compiler_options,
+            compile_request.grader_id.as_deref().unwrap_or(""),
+        ]
+        .into_iter()
+        .chain(
+            files
+                .iter()
+                .chain(&grader_files)
+                .flat_map(|file| [file.path.as_str(), file.content.as_str()]),
         ),
+    );
+    if let Some(entry) = compile_cache::get(&cache_key, blob_store.as_ref()).await {
+        return compiled(
+            entry,
+            true,
+            compile_request.store_executable,
+            blob_store.as_ref(),
+            spec.diagnostics_format,
+        )
+        .await;
+    }
+
+    write_files(tmp_dir.path(), &files)?;
+    // Only files with one of the language's source extensions are passed to the compiler, so
+    // headers are available to #include without being compiled on their own.
+    let is_source = |file: &SourceFile| {
+        Path::new(&file.path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| spec.source_extensions.iter().any(|e| e == extension))
+    };
+    // The grader's files are kept in directories of their own, whose random paths the user can't
+    // name, so that the user's files can't replace them. Only the headers are on the include path
+    // given by the language's `grader_flags`, so the user's code can't #include the grader's
+    // sources.
+    let (grader_sources, grader_headers): (Vec<_>, Vec<_>) =
+        grader_files.iter().cloned().partition(is_source);
+    let grader_source_dir = tempdir()?;
+    let grader_include_dir = tempdir()?;
+    write_files(grader_source_dir.path(), &grader_sources)?;
+    write_files(grader_include_dir.path(), &grader_headers)?;
+    let grader_source_path = grader_source_dir.path().to_str().unwrap();
+    let grader_include_path = grader_include_dir.path().to_str().unwrap();
+
+    let sources = files
+        .iter()
+        .filter(|file| is_source(file))
+        .map(|file| file.path.clone())
+        .chain(
+            grader_sources
+                .iter()
+                .map(|file| format!("{grader_source_path}/{}", file.path)),
+        )
+        .collect::<Vec<_>>();
+    let grader_flags = if grader_files.is_empty() {
+        Vec::new()
+    } else {
+        fill_argv(
+            &spec.grader_flags,
+            &[("grader_dir", grader_include_path)],
+            &[],
+        )
     };
-    let compile_output = run_command(
+
+    // Held until compilation finishes so that the header isn't evicted while in use.
+    let all_files = files
+        .iter()
+        .chain(&grader_files)
+        .cloned()
+        .collect::<Vec<_>>();
+    let pch = match precompiled_header(toolchain, &compiler_args, &all_files) {
+        Ok(pch) => pch,
+        Err(err) => {
+            println!("Warning: Failed to precompile headers: {err:#}");
+            PrecompiledHeader::default()
+        }
+    };
+
+    let program = program_filename.file_stem().unwrap().to_str().unwrap();
+    let out_dir = tmp_out_dir.path().to_str().unwrap();
+
+    let command = fill_argv(
+        &spec.compile_command,
+        &[
+            ("source", source),
+            ("program", program),
+            ("main_class", &main_class),
+            ("out_dir", out_dir),
+            (
+                "compiler",
+                toolchain.map_or("", |toolchain| &toolchain.compiler),
+            ),
+        ],
+        &[
+            ("sources", &sources),
+            ("options", &compiler_args),
+            ("pch_flags", &pch.flags),
+            ("grader_flags", &grader_flags),
+        ],
+    );
+    let command = CommandSpec::from_argv(&command, tmp_dir.path().to_owned())
+        .ok_or_else(|| anyhow!("Compile command for {} is empty", spec.id.0))?;
+    let mut compile_output = run_command(
         &command,
-        tmp_dir.path(),
         CommandOptions {
             stdin: Bytes::new(),
-            timeout_ms: 20000,
+            timeout_ms: compile_timeout_ms,
+            memory_limit_mb: compile_request.compile_memory_limit_mb,
         },
     )?;
+    drop(pch);
+    if !grader_files.is_empty() {
+        // Diagnostics are parsed from the scrubbed output, so they don't reveal the path either.
+        for output in [&mut compile_output.stdout, &mut compile_output.stderr] {
+            *output = output
+                .replace(grader_source_path, "grader")
+                .replace(grader_include_path, "grader");
+        }
+    }
 
-    let run_command = match compile_request.language {
-        Language::Cpp => "./program".to_owned(),
-        Language::Java21 => format!(
-            "java {}",
-            program_filename.file_stem().unwrap().to_str().unwrap()
-        ),
-        Language::Py12 => "python3.12 program.py".to_owned(),
-    };
+    let run_command = fill_argv(
+        &spec.run_command,
+        &[
+            ("source", source),
+            ("program", program),
+            ("main_class", &main_class),
+        ],
+        &[("options", &compiler_args)],
+    );
 
-    let base64_files = if ExitStatus::from_raw(compile_output.exit_code).success() {
-        if !Command::new("sh")
-            .arg("-c")
-            .arg("tar czf executable.tar.gz *")
-            .current_dir(tmp_out_dir.path())
-            .status()?
-            .success()
-        {
-            return Err(anyhow!("Failed to tar executable file"));
-        }
-        Some(BASE64_STANDARD.encode(fs::read(tmp_out_dir.path().join("executable.tar.gz"))?))
+    let packed_files = if ExitStatus::from_raw(compile_output.exit_code).success() {
+        Some(executable::pack_files(tmp_out_dir.path())?)
     } else {
         None
     };
 
-    let response = CompileResponse {
-        executable: base64_files.map(|files| Executable { files, run_command }),
+    tmp_dir.close()?;
+    grader_source_dir.close()?;
+    grader_include_dir.close()?;
+
+    let Some((files, content_hash)) = packed_files else {
+        return Ok(CompileResponse {
+            compile_status: CompileStatus::from_output(&compile_output),
+            executable: None,
+            executable_id: None,
+            diagnostics: spec
+                .diagnostics_format
+                .map(|format| diagnostics::parse(format, &compile_output.stderr))
+                .unwrap_or_default(),
+            compile_output,
+            cache_hit: false,
+        });
+    };
+
+    let entry = CacheEntry {
+        executable: Executable {
+            version: executable::FORMAT_VERSION,
+            manifest: Manifest {
+                language: Some(compile_request.language),
+                toolchain: toolchain.map(|toolchain| toolchain.id.clone()),
+                toolchain_version: version.map(str::to_owned),
+                compiler_options: compiler_args,
+                run_command,
+                grader_id: compile_request.grader_id,
+                source_files,
+                created_at: Some(executable::now()),
+                content_hash,
+            },
+            files,
+            signature: None,
+        },
         compile_output,
     };
+    if let Err(err) = compile_cache::put(&cache_key, &entry, blob_store.as_ref()).await {
+        println!("Warning: Failed to cache executable: {err}");
+    }
 
-    tmp_dir.close()?;
+    compiled(
+        entry,
+        false,
+        compile_request.store_executable,
+        blob_store.as_ref(),
+        spec.diagnostics_format,
+    )
+    .await
+}
 
-    Ok(response)
+/// Writes `files` under `dir`, creating subdirectories as needed.
+fn write_files(dir: &Path, files: &[SourceFile]) -> Result<()> {
+    for file in files {
+        let path = dir.join(&file.path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut source_file = File::create(path)?;
+        source_file.write_all(file.content.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// The response for a successful compilation.
+async fn compiled(
+    mut entry: CacheEntry,
+    cache_hit: bool,
+    store_executable: bool,
+    blob_store: &dyn BlobStore,
+    diagnostics_format: Option<DiagnosticsFormat>,
+) -> Result<CompileResponse> {
+    // Executables are signed when they are returned rather than when they are cached, so that
+    // cached executables stay valid if the signing key changes.
+    entry.executable.sign()?;
+    let executable_id = if store_executable {
+        Some(entry.executable.store(blob_store).await?)
+    } else {
+        None
+    };
+
+    Ok(CompileResponse {
+        compile_status: CompileStatus::Success,
+        executable: Some(entry.executable),
+        executable_id,
+        diagnostics: diagnostics_format
+            .map(|format| diagnostics::parse(format, &entry.compile_output.stderr))
+            .unwrap_or_default(),
+        compile_output: entry.compile_output,
+        cache_hit,
+    })
 }
 
 pub async fn compile_handler(
+    State(state): State<AppState>,
     Json(payload): Json<CompileRequest>,
 ) -> Result<Json<CompileResponse>, AppError> {
-    Ok(Json(compile(payload)?))
+    let mut response = run_blocking(compile(payload, state.blob_store)).await?;
+    // Callers that compile and then execute need the executable, but clients that stored it don't.
+    if response.executable_id.is_some() {
+        response.executable = None;
+    }
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_relative_paths() {
+        for path in ["main.cpp", "Main.java", "lib/helper.py", "a-b_c.d/e.h"] {
+            validate_source_path(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_source_directory() {
+        for path in [
+            "../x",
+            "a/../../x",
+            "/abs",
+            "a//b",
+            "./a",
+            "a/./b",
+            "a/",
+            "",
+        ] {
+            assert!(validate_source_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn rejects_options_and_unusual_characters() {
+        for path in [
+            "-o",
+            "a/-o",
+            "my file.cpp",
+            "a\tb",
+            "é.cpp",
+            "main.cpp;id",
+            "$x",
+        ] {
+            assert!(validate_source_path(path).is_err(), "{path}");
+        }
+    }
 }
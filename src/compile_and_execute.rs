@@ -1,11 +1,32 @@
-use axum::{extract::State, Json};
+use std::{
+    convert::Infallible,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
+    Json,
+};
 use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+use tracing::error;
 
 use crate::{
-    compile::{compile, CompileRequest},
-    error::AppError,
-    execute::{execute, ExecuteOptions, ExecuteRequest, ExecuteResponse},
-    run_command::CommandOutput,
+    blob_store::BlobStore,
+    compile::{compile, CompileRequest, CompileStatus},
+    diagnostics::Diagnostic,
+    error::{AppError, HTTPError},
+    execute::{run_executable, ExecuteOptions, ExecuteResponse},
+    run_blocking,
+    run_command::{CommandOutput, OutputStream},
     AppState,
 };
 
@@ -22,7 +43,10 @@ pub struct CompileAndExecuteRequest {
 /// Response for POST /compile-and-execute
 #[derive(Serialize)]
 pub struct CompileAndExecuteResponse {
+    pub compile_status: CompileStatus,
     pub compile: CommandOutput,
+    /// Errors and warnings parsed from `compile`.
+    pub diagnostics: Vec<Diagnostic>,
     /// None if the program failed to compile.
     pub execute: Option<ExecuteResponse>,
 }
@@ -31,23 +55,287 @@ pub async fn compile_and_execute_handler(
     State(state): State<AppState>,
     Json(payload): Json<CompileAndExecuteRequest>,
 ) -> Result<Json<CompileAndExecuteResponse>, AppError> {
-    let compile_output = compile(payload.compile)?;
+    Ok(Json(
+        run_blocking(compile_and_execute(payload, state.blob_store)).await?,
+    ))
+}
+
+async fn compile_and_execute(
+    payload: CompileAndExecuteRequest,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<CompileAndExecuteResponse> {
+    let compile_output = compile(payload.compile, blob_store.clone()).await?;
     let execute_output = if let Some(executable) = compile_output.executable {
-        Some(
-            execute(
-                ExecuteRequest {
-                    executable,
-                    options: payload.execute,
-                },
-                state.s3_client,
-            )
-            .await?,
-        )
+        Some(run_executable(&executable, payload.execute, blob_store, None).await?)
     } else {
         None
     };
-    Ok(Json(CompileAndExecuteResponse {
+    Ok(CompileAndExecuteResponse {
+        compile_status: compile_output.compile_status,
         compile: compile_output.compile_output,
+        diagnostics: compile_output.diagnostics,
         execute: execute_output,
-    }))
+    })
+}
+
+/// The `compile` event of POST /compile-and-execute/stream
+#[derive(Serialize)]
+struct CompileEvent<'a> {
+    compile_status: CompileStatus,
+    compile: &'a CommandOutput,
+    diagnostics: &'a [Diagnostic],
+}
+
+/// The `error` event of POST /compile-and-execute/stream, sent instead of the error response that
+/// /compile-and-execute would return.
+#[derive(Serialize)]
+struct ErrorEvent {
+    status: u16,
+    message: String,
+}
+
+/// Limit on the output streamed as `stdout` and `stderr` events, like the 5 MB of output that
+/// /execute returns. The program keeps running past it, but the rest of its output is only in the
+/// (truncated) `result` event.
+const MAX_STREAMED_BYTES: usize = 5_000_000;
+
+/// Turns output chunks into the text of `stdout` and `stderr` events, up to
+/// [`MAX_STREAMED_BYTES`].
+struct OutputEvents {
+    chunkers: [Utf8Chunker; 2],
+    remaining_bytes: usize,
+}
+
+impl OutputEvents {
+    fn new() -> OutputEvents {
+        OutputEvents {
+            chunkers: Default::default(),
+            remaining_bytes: MAX_STREAMED_BYTES,
+        }
+    }
+
+    fn push(&mut self, stream: OutputStream, data: &[u8]) -> Option<String> {
+        if self.remaining_bytes == 0 {
+            return None;
+        }
+        let text = self.chunkers[stream as usize].push(data);
+        self.limit(text)
+    }
+
+    fn finish(&mut self, stream: OutputStream) -> Option<String> {
+        if self.remaining_bytes == 0 {
+            return None;
+        }
+        let text = self.chunkers[stream as usize].finish();
+        self.limit(text)
+    }
+
+    fn limit(&mut self, mut text: String) -> Option<String> {
+        if text.is_empty() {
+            return None;
+        }
+        if text.len() > self.remaining_bytes {
+            let mut len = self.remaining_bytes;
+            while !text.is_char_boundary(len) {
+                len -= 1;
+            }
+            text.truncate(len);
+            text += "\n[Truncated]";
+            self.remaining_bytes = 0;
+        } else {
+            self.remaining_bytes -= text.len();
+        }
+        Some(text)
+    }
+}
+
+/// Turns output chunks into text without splitting UTF-8 characters across chunks.
+#[derive(Default)]
+struct Utf8Chunker {
+    pending: Vec<u8>,
+}
+
+impl Utf8Chunker {
+    fn push(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        let complete_len = self.pending.len() - incomplete_suffix_len(&self.pending);
+        let text = String::from_utf8_lossy(&self.pending[..complete_len]).into_owned();
+        self.pending.drain(..complete_len);
+        text
+    }
+
+    fn finish(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned()
+    }
+}
+
+/// The length of the character at the end of `data` that is cut off, if any.
+fn incomplete_suffix_len(data: &[u8]) -> usize {
+    for (i, &byte) in data.iter().rev().take(3).enumerate() {
+        // Skip continuation bytes to find the start of the last character.
+        if byte & 0xC0 != 0x80 {
+            let len = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if len > i + 1 { i + 1 } else { 0 };
+        }
+    }
+    0
+}
+
+async fn stream_compile_and_execute(
+    payload: CompileAndExecuteRequest,
+    blob_store: Arc<dyn BlobStore>,
+    events: mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let compile_output = compile(payload.compile, blob_store.clone()).await?;
+    let _ = events.send(Event::default().event("compile").json_data(CompileEvent {
+        compile_status: compile_output.compile_status,
+        compile: &compile_output.compile_output,
+        diagnostics: &compile_output.diagnostics,
+    })?);
+    let Some(executable) = compile_output.executable else {
+        return Ok(());
+    };
+
+    let output_events = Arc::new(Mutex::new(OutputEvents::new()));
+    let on_output = {
+        let output_events = output_events.clone();
+        let events = events.clone();
+        Arc::new(move |stream: OutputStream, data: &[u8]| {
+            let text = output_events.lock().unwrap().push(stream, data);
+            // Sending fails once the client has disconnected, and then nobody is waiting for the
+            // program to finish.
+            let is_sent = match text {
+                Some(text) => events.send(output_event(stream, &text)).is_ok(),
+                None => !events.is_closed(),
+            };
+            if is_sent {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        })
+    };
+    let execute_output =
+        run_executable(&executable, payload.execute, blob_store, Some(on_output)).await?;
+
+    for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+        if let Some(text) = output_events.lock().unwrap().finish(stream) {
+            let _ = events.send(output_event(stream, &text));
+        }
+    }
+    let _ = events.send(Event::default().event("result").json_data(execute_output)?);
+    Ok(())
+}
+
+fn output_event(stream: OutputStream, text: &str) -> Event {
+    let name = match stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    };
+    // JSON strings, because SSE would lose a trailing newline in plain data.
+    Event::default()
+        .event(name)
+        .json_data(text)
+        .expect("Strings serialize to JSON")
+}
+
+/// Streaming version of POST /compile-and-execute, as Server-Sent Events:
+///
+/// - `compile`: the compile status, output and diagnostics.
+/// - `stdout` and `stderr`: output as the program produces it, as JSON strings, up to 5 MB in
+///   total. The program is killed if the client disconnects while it runs.
+/// - `result`: the same response as `/execute`, once the program has finished.
+/// - `error`: sent instead if the request fails, with the HTTP `status` and `message`.
+pub async fn compile_and_execute_stream_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CompileAndExecuteRequest>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (events, receiver) = mpsc::unbounded_channel();
+    let runtime = Handle::current();
+    // Running the program blocks the thread, and the events need to be sent while it runs.
+    tokio::task::spawn_blocking(move || {
+        let result = runtime.block_on(stream_compile_and_execute(
+            payload,
+            state.blob_store,
+            events.clone(),
+        ));
+        if let Err(err) = result {
+            let error = match err.downcast_ref::<HTTPError>() {
+                Some(HTTPError(status, message)) => ErrorEvent {
+                    status: status.as_u16(),
+                    message: message.clone(),
+                },
+                None => {
+                    error!("Returning Internal Server Error: {:?}", err);
+                    ErrorEvent {
+                        status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        message: format!("Internal Server Error: {:?}", err),
+                    }
+                }
+            };
+            let event = Event::default().event("error").json_data(error);
+            let _ = events.send(event.expect("Errors serialize to JSON"));
+        }
+    });
+
+    let stream = UnboundedReceiverStream::new(receiver);
+    Sse::new(stream.map(Ok)).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_characters_split_across_chunks_together() {
+        let data = "aé€😀".as_bytes();
+        for split in 0..=data.len() {
+            let mut chunker = Utf8Chunker::default();
+            let mut text = chunker.push(&data[..split]);
+            text += &chunker.push(&data[split..]);
+            text += &chunker.finish();
+            assert_eq!(text, "aé€😀", "split at {split}");
+        }
+    }
+
+    #[test]
+    fn finds_incomplete_suffixes() {
+        let euro = "€".as_bytes();
+        assert_eq!(incomplete_suffix_len(b"abc"), 0);
+        assert_eq!(incomplete_suffix_len(euro), 0);
+        assert_eq!(incomplete_suffix_len(&euro[..1]), 1);
+        assert_eq!(incomplete_suffix_len(&euro[..2]), 2);
+        assert_eq!(incomplete_suffix_len(&"😀".as_bytes()[..3]), 3);
+        // Invalid bytes are passed through to be replaced, not held back.
+        assert_eq!(incomplete_suffix_len(&[0xFF]), 0);
+    }
+
+    #[test]
+    fn truncates_output_at_the_limit() {
+        let mut output_events = OutputEvents::new();
+        let chunk = "a".repeat(MAX_STREAMED_BYTES - 1) + "é";
+        let text = output_events
+            .push(OutputStream::Stdout, chunk.as_bytes())
+            .unwrap();
+        // "é" doesn't fit in the last byte, so it is cut off as a whole.
+        assert_eq!(text.len(), MAX_STREAMED_BYTES - 1 + "\n[Truncated]".len());
+        assert!(text.ends_with("a\n[Truncated]"));
+
+        assert_eq!(output_events.push(OutputStream::Stderr, b"more"), None);
+        assert_eq!(output_events.finish(OutputStream::Stdout), None);
+    }
+
+    #[test]
+    fn shares_the_limit_between_streams() {
+        let mut output_events = OutputEvents::new();
+        let stdout = "a".repeat(MAX_STREAMED_BYTES - 2);
+        output_events.push(OutputStream::Stdout, stdout.as_bytes());
+        let text = output_events.push(OutputStream::Stderr, b"bcd").unwrap();
+        assert_eq!(text, "bc\n[Truncated]");
+    }
 }
@@ -0,0 +1,123 @@
+//! Cache of compiled executables.
+//!
+//! The IDE often runs the same code several times with different input, and compiling C++ can take
+//! seconds, so successful compilations are cached by a hash of everything that affects the output:
+//! the language, toolchain and its version, compiler options, and every source file.
+//!
+//! Entries are stored in /tmp, which only lasts as long as the lambda container. If
+//! `COMPILE_CACHE_S3` is set, entries are also stored in the blob store so that they are shared
+//! between containers.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{blob_store::BlobStore, executable::Executable, run_command::CommandOutput};
+
+const CACHE_DIR: &str = "/tmp/compile-cache";
+
+/// Once the local cache grows past this, the least recently used entries are removed.
+const MAX_CACHE_BYTES: u64 = 100_000_000;
+
+#[derive(Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub executable: Executable,
+    /// Kept so that warnings are still shown on a cache hit.
+    pub compile_output: CommandOutput,
+}
+
+/// Builds a cache key. Each part is length-prefixed so that different parts can't run together
+/// into the same key.
+pub fn cache_key<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn local_path(key: &str) -> PathBuf {
+    Path::new(CACHE_DIR).join(format!("{key}.json"))
+}
+
+fn use_s3() -> bool {
+    env::var("COMPILE_CACHE_S3").is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+pub async fn get(key: &str, blob_store: &dyn BlobStore) -> Option<CacheEntry> {
+    let path = local_path(key);
+    if let Ok(data) = fs::read(&path) {
+        // Touch the entry so that it counts as recently used.
+        let _ =
+            fs::File::open(&path).and_then(|file| file.set_modified(std::time::SystemTime::now()));
+        return serde_json::from_slice(&data).ok();
+    }
+
+    if !use_s3() {
+        return None;
+    }
+    let data = blob_store
+        .get(&format!("compile-cache/{key}.json"))
+        .await
+        .ok()??;
+    let entry = serde_json::from_slice(&data).ok()?;
+    if let Err(err) = write_local(key, &data) {
+        println!("Warning: Failed to write compile cache entry: {err}");
+    }
+    Some(entry)
+}
+
+pub async fn put(key: &str, entry: &CacheEntry, blob_store: &dyn BlobStore) -> Result<()> {
+    let data = serde_json::to_vec(entry)?;
+    write_local(key, &data)?;
+
+    if use_s3() {
+        blob_store
+            .put(
+                &format!("compile-cache/{key}.json"),
+                Bytes::from(data),
+                "application/json",
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+fn write_local(key: &str, data: &[u8]) -> Result<()> {
+    fs::create_dir_all(CACHE_DIR)?;
+    evict(data.len() as u64)?;
+    // Write to a temporary file first so that a concurrent reader never sees a partial entry.
+    let tmp_path = Path::new(CACHE_DIR).join(format!("{key}.json.{}", uuid::Uuid::new_v4()));
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, local_path(key))?;
+    Ok(())
+}
+
+/// Removes the least recently used entries until there is room for `incoming_bytes` more.
+fn evict(incoming_bytes: u64) -> Result<()> {
+    let mut entries = fs::read_dir(CACHE_DIR)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect::<Vec<_>>();
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum::<u64>() + incoming_bytes;
+    entries.sort_by_key(|(_, _, modified)| *modified);
+    for (path, len, _) in entries {
+        if total <= MAX_CACHE_BYTES {
+            break;
+        }
+        // Another request may have removed it already.
+        let _ = fs::remove_file(path);
+        total -= len;
+    }
+    Ok(())
+}
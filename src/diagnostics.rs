@@ -0,0 +1,259 @@
+//! Parses compiler output into structured diagnostics, so that the IDE can show errors inline.
+//!
+//! We parse the human-readable output instead of using GCC's `-fdiagnostics-format=json`, since
+//! the JSON format replaces the text output that is shown to users in `compile_output.stderr`,
+//! and Clang and javac have no equivalent.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// How a language's compiler reports errors.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticsFormat {
+    /// `file:line:column: severity: message`, used by GCC and Clang.
+    Gcc,
+    /// `File.java:line: severity: message`, followed by the source line and a caret.
+    Javac,
+    /// A Python traceback ending with `SyntaxError: message`.
+    Python,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diagnostic {
+    /// Path as reported by the compiler, like "program.cpp" or a system header.
+    pub file: String,
+    /// 1-indexed.
+    pub line: u32,
+    /// 1-indexed. None if the compiler didn't report one.
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+    /// Notes with more context for this diagnostic, like candidate functions.
+    pub notes: Vec<Diagnostic>,
+}
+
+pub fn parse(format: DiagnosticsFormat, stderr: &str) -> Vec<Diagnostic> {
+    match format {
+        DiagnosticsFormat::Gcc => parse_gcc(stderr),
+        DiagnosticsFormat::Javac => parse_javac(stderr),
+        DiagnosticsFormat::Python => parse_python(stderr).into_iter().collect(),
+    }
+}
+
+fn parse_gcc(stderr: &str) -> Vec<Diagnostic> {
+    let re = Regex::new(
+        r"^(?<file>[^:\s][^:]*):(?<line>\d+):(?<column>\d+): (?<severity>fatal error|error|warning|note): (?<message>.*)$",
+    )
+    .unwrap();
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for captures in stderr.lines().filter_map(|line| re.captures(line)) {
+        let severity = match &captures["severity"] {
+            "warning" => Severity::Warning,
+            "note" => Severity::Note,
+            _ => Severity::Error,
+        };
+        let diagnostic = Diagnostic {
+            file: captures["file"].to_owned(),
+            line: captures["line"].parse().unwrap_or(0),
+            column: captures["column"].parse().ok(),
+            severity,
+            message: captures["message"].trim().to_owned(),
+            notes: Vec::new(),
+        };
+        match diagnostics.last_mut() {
+            Some(parent) if severity == Severity::Note => parent.notes.push(diagnostic),
+            _ => diagnostics.push(diagnostic),
+        }
+    }
+    diagnostics
+}
+
+fn parse_javac(stderr: &str) -> Vec<Diagnostic> {
+    let re =
+        Regex::new(r"^(?<file>.+\.java):(?<line>\d+): (?<severity>error|warning): (?<message>.*)$")
+            .unwrap();
+    let caret_re = Regex::new(r"^\s*\^\s*$").unwrap();
+    let summary_re = Regex::new(r"^\d+ (errors?|warnings?)$").unwrap();
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut lines = stderr.lines();
+    while let Some(line) = lines.next() {
+        if let Some(captures) = re.captures(line) {
+            diagnostics.push(Diagnostic {
+                file: captures["file"].to_owned(),
+                line: captures["line"].parse().unwrap_or(0),
+                column: None,
+                severity: if &captures["severity"] == "warning" {
+                    Severity::Warning
+                } else {
+                    Severity::Error
+                },
+                message: captures["message"].to_owned(),
+                notes: Vec::new(),
+            });
+            // Skip the line of source code that javac prints after the message.
+            lines.next();
+        } else if let Some(diagnostic) = diagnostics.last_mut() {
+            if caret_re.is_match(line) {
+                diagnostic.column = line.find('^').map(|index| index as u32 + 1);
+            } else if !line.trim().is_empty() && !summary_re.is_match(line) {
+                // Details such as "symbol: variable x"
+                diagnostic.notes.push(Diagnostic {
+                    file: diagnostic.file.clone(),
+                    line: diagnostic.line,
+                    column: None,
+                    severity: Severity::Note,
+                    message: line.trim().to_owned(),
+                    notes: Vec::new(),
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Parses a traceback like
+///
+/// ```text
+///   File "program.py", line 2
+///     print(x
+///          ^
+/// SyntaxError: '(' was never closed
+/// ```
+fn parse_python(stderr: &str) -> Option<Diagnostic> {
+    let frame_re = Regex::new(r#"^\s*File "(?<file>[^"]+)", line (?<line>\d+)"#).unwrap();
+    let error_re = Regex::new(r"^(?<type>\w+(Error|Exception|Warning)): (?<message>.*)$").unwrap();
+
+    let (file, line) = stderr
+        .lines()
+        .rev()
+        .filter_map(|line| frame_re.captures(line))
+        .find(|captures| !captures["file"].starts_with('<'))
+        .map(|captures| (captures["file"].to_owned(), captures["line"].parse().ok()))?;
+    let captures = stderr
+        .lines()
+        .rev()
+        .find_map(|line| error_re.captures(line))?;
+
+    Some(Diagnostic {
+        file,
+        line: line.unwrap_or(0),
+        column: None,
+        severity: Severity::Error,
+        message: format!("{}: {}", &captures["type"], &captures["message"]),
+        notes: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gcc_errors_with_notes() {
+        let stderr = "\
+program.cpp: In function 'int main()':
+program.cpp:5:10: error: cannot convert 'std::string' to 'int'
+    5 |   f(std::string());
+      |          ^~~~~~~~
+program.cpp:2:8: note:   initializing argument 1 of 'void f(int)'
+    2 | void f(int);
+      |        ^~~
+program.cpp:4:7: warning: unused variable 'x' [-Wunused-variable]
+";
+        let diagnostics = parse(DiagnosticsFormat::Gcc, stderr);
+        assert_eq!(diagnostics.len(), 2);
+
+        let error = &diagnostics[0];
+        assert_eq!(error.file, "program.cpp");
+        assert_eq!((error.line, error.column), (5, Some(10)));
+        assert_eq!(error.severity, Severity::Error);
+        assert_eq!(error.message, "cannot convert 'std::string' to 'int'");
+        assert_eq!(error.notes.len(), 1);
+        assert_eq!(error.notes[0].line, 2);
+        assert_eq!(
+            error.notes[0].message,
+            "initializing argument 1 of 'void f(int)'"
+        );
+
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].line, 4);
+    }
+
+    #[test]
+    fn parses_gcc_fatal_errors_in_headers() {
+        let stderr = "\
+In file included from program.cpp:1:
+lib/util.h:3:10: fatal error: missing.h: No such file or directory
+compilation terminated.
+";
+        let diagnostics = parse(DiagnosticsFormat::Gcc, stderr);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, "lib/util.h");
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            diagnostics[0].message,
+            "missing.h: No such file or directory"
+        );
+    }
+
+    #[test]
+    fn parses_javac_errors_with_column_and_details() {
+        let stderr = "\
+Main.java:3: error: cannot find symbol
+    int x = y;
+            ^
+  symbol:   variable y
+  location: class Main
+1 error
+";
+        let diagnostics = parse(DiagnosticsFormat::Javac, stderr);
+        assert_eq!(diagnostics.len(), 1);
+        let error = &diagnostics[0];
+        assert_eq!(error.file, "Main.java");
+        assert_eq!((error.line, error.column), (3, Some(13)));
+        assert_eq!(error.message, "cannot find symbol");
+        let notes = error
+            .notes
+            .iter()
+            .map(|note| note.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(notes, ["symbol:   variable y", "location: class Main"]);
+    }
+
+    #[test]
+    fn parses_python_syntax_errors() {
+        let stderr = r#"Traceback (most recent call last):
+  File "<string>", line 1, in <module>
+  File "program.py", line 1
+    print(x
+         ^
+SyntaxError: '(' was never closed
+"#;
+        let diagnostics = parse(DiagnosticsFormat::Python, stderr);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, "program.py");
+        assert_eq!(diagnostics[0].line, 1);
+        assert_eq!(diagnostics[0].message, "SyntaxError: '(' was never closed");
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        assert!(parse(
+            DiagnosticsFormat::Gcc,
+            "collect2: error: ld returned 1 exit status\n"
+        )
+        .is_empty());
+        assert!(parse(DiagnosticsFormat::Python, "").is_empty());
+    }
+}
@@ -30,7 +30,7 @@ pub struct AppError(anyhow::Error);
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         match self.0.downcast_ref::<HTTPError>() {
-            Some(HTTPError(status, message)) => (status.clone(), message.clone()).into_response(),
+            Some(HTTPError(status, message)) => (*status, message.clone()).into_response(),
             None => {
                 error!("Returning Internal Server Error: {:?}", self.0);
                 (
@@ -0,0 +1,441 @@
+//! The executable format returned by `/compile` and accepted by `/execute`.
+//!
+//! An executable is an envelope holding a [`Manifest`], which records how the executable was
+//! built and how to run it, and the compiled files as a base64 zstd-compressed tar archive.
+//!
+//! Executables from before the envelope existed, with a base64 .tar.gz archive and the run command
+//! at the top level, can still be parsed, and are treated as format version 0. They were never
+//! signed, though, so `/execute` rejects them unless `ALLOW_UNSIGNED_EXECUTABLES` is set, and
+//! clients holding one must compile the program again.
+//!
+//! Since `/execute` runs whatever `run_command` it is given, executables are signed with an HMAC
+//! of the manifest (which includes the hash of the files) using the secret in
+//! `EXECUTABLE_SIGNING_KEY`, and `/execute` only runs executables with a valid signature. The
+//! server doesn't start without the key unless `ALLOW_UNSIGNED_EXECUTABLES=1` is set to run any
+//! executable, for local development. Executables compiled by the same request, as in
+//! `/compile-and-execute` and jobs, are run without a signature check.
+//!
+//! Executables can also be stored in the blob store with [`Executable::store`] and executed by
+//! id, so that large binaries don't need to be uploaded with every `/execute` request.
+
+use std::{
+    env,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use axum::http::StatusCode;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{archive, blob_store::BlobStore, error::HTTPError, types::Language};
+
+/// Version of the executable format produced by this server.
+pub const FORMAT_VERSION: u32 = 1;
+
+// for the future, when implementing checker / scorer support, we probably want to add an "additional_files" field to executable.
+// checker: https://probgate.org/viewsolution.php?grader_id=557
+// needs 3 files, input, output, answer
+// scorer: https://probgate.org/viewsolution.php?scorer_id=4
+// needs N files, one for each test case
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    /// The language the executable was compiled from, used to apply its time multiplier. None
+    /// for legacy executables that didn't record it.
+    pub language: Option<Language>,
+
+    /// The toolchain it was compiled with, like "gcc", for languages with several.
+    #[serde(default)]
+    pub toolchain: Option<String>,
+
+    /// Compiler / runtime version, like the first line of `g++ --version`.
+    #[serde(default)]
+    pub toolchain_version: Option<String>,
+
+    /// Compiler options, split into arguments.
+    #[serde(default)]
+    pub compiler_options: Vec<String>,
+
+    /// Program and arguments that run the executable.
+    pub run_command: Vec<String>,
+
+    /// Set if the executable was linked against a grader, whose result is then returned as
+    /// `grader_output` when executing.
+    #[serde(default)]
+    pub grader_id: Option<String>,
+
+    /// Paths of the user's source files, used to find the user's code in stack traces. Doesn't
+    /// include grader files.
+    #[serde(default)]
+    pub source_files: Vec<String>,
+
+    /// Unix time in seconds when the executable was compiled. None for legacy executables.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+
+    /// Hex SHA-256 of the base64-decoded `files`, checked before the executable is run.
+    pub content_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "ExecutableFormat")]
+pub struct Executable {
+    pub version: u32,
+    pub manifest: Manifest,
+    /// base64 zstd-compressed tar archive, or a gzip-compressed one for version 0.
+    pub files: String,
+    /// base64 HMAC-SHA256 of `version` and `manifest`. None if the server has no signing key.
+    pub signature: Option<String>,
+}
+
+/// The executable formats that are accepted.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExecutableFormat {
+    Envelope {
+        version: u32,
+        manifest: Manifest,
+        files: String,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    Legacy {
+        files: String,
+        #[serde(deserialize_with = "deserialize_run_command")]
+        run_command: Vec<String>,
+        #[serde(default)]
+        language: Option<Language>,
+        #[serde(default)]
+        grader_id: Option<String>,
+        #[serde(default)]
+        source_files: Vec<String>,
+    },
+}
+
+/// Legacy executables have a shell command instead of an argument list, which is run with
+/// `sh -c`.
+fn deserialize_run_command<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RunCommand {
+        Argv(Vec<String>),
+        Shell(String),
+    }
+
+    Ok(match RunCommand::deserialize(deserializer)? {
+        RunCommand::Argv(argv) => argv,
+        RunCommand::Shell(command) => vec!["sh".to_owned(), "-c".to_owned(), command],
+    })
+}
+
+impl Manifest {
+    /// Whether the program is run by a shell, which is only the case for legacy executables.
+    pub fn runs_in_shell(&self) -> bool {
+        matches!(self.run_command.as_slice(), [shell, flag, ..] if shell == "sh" && flag == "-c")
+    }
+}
+
+impl TryFrom<ExecutableFormat> for Executable {
+    type Error = String;
+
+    fn try_from(format: ExecutableFormat) -> Result<Self, Self::Error> {
+        Ok(match format {
+            ExecutableFormat::Envelope {
+                version,
+                manifest,
+                files,
+                signature,
+            } => Executable {
+                version,
+                manifest,
+                files,
+                signature,
+            },
+            ExecutableFormat::Legacy {
+                files,
+                run_command,
+                language,
+                grader_id,
+                source_files,
+            } => {
+                let data = BASE64_STANDARD
+                    .decode(&files)
+                    .map_err(|err| format!("Invalid executable files: {err}"))?;
+                Executable {
+                    version: 0,
+                    manifest: Manifest {
+                        language,
+                        toolchain: None,
+                        toolchain_version: None,
+                        compiler_options: Vec::new(),
+                        run_command,
+                        grader_id,
+                        source_files,
+                        created_at: None,
+                        content_hash: content_hash(&data),
+                    },
+                    files,
+                    signature: None,
+                }
+            }
+        })
+    }
+}
+
+fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Packs the files in `dir` for an executable, returning them base64 encoded along with their
+/// content hash for the manifest.
+pub fn pack_files(dir: &Path) -> Result<(String, String)> {
+    let data = zstd::encode_all(&archive::pack(dir)?[..], zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    Ok((BASE64_STANDARD.encode(&data), content_hash(&data)))
+}
+
+/// The current time for `created_at`.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn signing_key() -> Option<Vec<u8>> {
+    env::var("EXECUTABLE_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes)
+}
+
+fn allow_unsigned() -> bool {
+    env::var("ALLOW_UNSIGNED_EXECUTABLES").is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Fails if executables can't be verified, so that a server without `EXECUTABLE_SIGNING_KEY`
+/// refuses to start instead of failing every `/execute` request.
+pub fn check_signing_key() -> Result<()> {
+    if signing_key().is_none() && !allow_unsigned() {
+        return Err(anyhow!(
+            "EXECUTABLE_SIGNING_KEY is not set. Set ALLOW_UNSIGNED_EXECUTABLES=1 to run unsigned \
+             executables in local development."
+        ));
+    }
+    Ok(())
+}
+
+impl Executable {
+    fn mac(&self, key: &[u8]) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+        // The manifest includes the hash of the files, so this covers the files too.
+        mac.update(&serde_json::to_vec(&(self.version, &self.manifest))?);
+        Ok(mac)
+    }
+
+    /// Signs the executable if a signing key is configured.
+    pub fn sign(&mut self) -> Result<()> {
+        self.signature = match signing_key() {
+            Some(key) => Some(self.signature_with(&key)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn signature_with(&self, key: &[u8]) -> Result<String> {
+        Ok(BASE64_STANDARD.encode(self.mac(key)?.finalize().into_bytes()))
+    }
+
+    /// Checks that the executable was signed by this server and hasn't been modified since.
+    pub fn verify(&self) -> Result<()> {
+        if allow_unsigned() {
+            return Ok(());
+        }
+        let key = signing_key().ok_or_else(|| {
+            HTTPError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "EXECUTABLE_SIGNING_KEY is not set. Set ALLOW_UNSIGNED_EXECUTABLES=1 to run \
+                 unsigned executables in local development."
+                    .to_owned(),
+            )
+        })?;
+        self.verify_with(&key)
+    }
+
+    fn verify_with(&self, key: &[u8]) -> Result<()> {
+        let is_valid = self.signature.as_ref().is_some_and(|signature| {
+            BASE64_STANDARD.decode(signature).is_ok_and(|signature| {
+                self.mac(key)
+                    .is_ok_and(|mac| mac.verify_slice(&signature).is_ok())
+            })
+        });
+        if !is_valid {
+            return Err(HTTPError(
+                StatusCode::FORBIDDEN,
+                "Executable is unsigned or has been modified. Executables must come from /compile."
+                    .to_owned(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Checks the files against the manifest and unpacks them into `dir`.
+    pub fn unpack(&self, dir: &Path) -> Result<()> {
+        let data = BASE64_STANDARD.decode(&self.files).map_err(|err| {
+            HTTPError(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid executable files: {err}"),
+            )
+        })?;
+        if content_hash(&data) != self.manifest.content_hash {
+            return Err(HTTPError(
+                StatusCode::BAD_REQUEST,
+                "Executable files don't match the manifest's content hash".to_owned(),
+            )
+            .into());
+        }
+
+        match self.version {
+            0 => archive::unpack(GzDecoder::new(&data[..]), dir),
+            1 => archive::unpack(zstd::Decoder::new(&data[..])?, dir),
+            version => Err(HTTPError(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported executable format version {version}"),
+            )
+            .into()),
+        }
+    }
+
+    /// Stores the executable at `executables/{id}.json` in the blob store and returns its id.
+    pub async fn store(&self, blob_store: &dyn BlobStore) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        blob_store
+            .put(
+                &format!("executables/{id}.json"),
+                Bytes::from(serde_json::to_vec(self)?),
+                "application/json",
+            )
+            .await?;
+        Ok(id)
+    }
+
+    /// Loads an executable saved by [`Executable::store`]. Stored executables were written by this
+    /// server, so their signature isn't checked.
+    pub async fn load(blob_store: &dyn BlobStore, executable_id: &str) -> Result<Executable> {
+        if Uuid::parse_str(executable_id).is_err() {
+            return Err(
+                HTTPError(StatusCode::BAD_REQUEST, "Invalid executable_id".to_string()).into(),
+            );
+        }
+
+        let data = blob_store
+            .get(&format!("executables/{executable_id}.json"))
+            .await?
+            .ok_or_else(|| {
+                HTTPError(
+                    StatusCode::NOT_FOUND,
+                    format!("Executable {executable_id} not found"),
+                )
+            })?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::blob_store::MemoryBlobStore;
+
+    const KEY: &[u8] = b"test key";
+
+    fn signed_executable() -> Executable {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("program"), "#!/bin/sh\necho hi\n").unwrap();
+        let (files, content_hash) = pack_files(dir.path()).unwrap();
+        let mut executable = Executable {
+            version: FORMAT_VERSION,
+            manifest: Manifest {
+                language: Some(Language("cpp".to_owned())),
+                toolchain: None,
+                toolchain_version: None,
+                compiler_options: Vec::new(),
+                run_command: vec!["./program".to_owned()],
+                grader_id: None,
+                source_files: vec!["program.cpp".to_owned()],
+                created_at: Some(now()),
+                content_hash,
+            },
+            files,
+            signature: None,
+        };
+        executable.signature = Some(executable.signature_with(KEY).unwrap());
+        executable
+    }
+
+    #[test]
+    fn accepts_signed_executables() {
+        let executable = signed_executable();
+        executable.verify_with(KEY).unwrap();
+        executable.unpack(tempdir().unwrap().path()).unwrap();
+    }
+
+    #[test]
+    fn rejects_changed_run_commands() {
+        let mut executable = signed_executable();
+        executable.manifest.run_command = vec!["sh".to_owned(), "-c".to_owned(), "id".to_owned()];
+        assert!(executable.verify_with(KEY).is_err());
+    }
+
+    #[test]
+    fn rejects_changed_files() {
+        let mut executable = signed_executable();
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("program"), "#!/bin/sh\nid\n").unwrap();
+        executable.files = pack_files(dir.path()).unwrap().0;
+        // The signature only covers the manifest, whose content hash no longer matches.
+        executable.verify_with(KEY).unwrap();
+        let error = executable.unpack(tempdir().unwrap().path()).unwrap_err();
+        assert!(error.to_string().contains("content hash"), "{error}");
+    }
+
+    #[test]
+    fn rejects_missing_signatures() {
+        let mut executable = signed_executable();
+        executable.signature = None;
+        assert!(executable.verify_with(KEY).is_err());
+    }
+
+    #[test]
+    fn rejects_signatures_with_other_keys() {
+        let mut executable = signed_executable();
+        executable.signature = Some(executable.signature_with(b"other key").unwrap());
+        assert!(executable.verify_with(KEY).is_err());
+    }
+
+    #[tokio::test]
+    async fn store_and_load() {
+        let blob_store = MemoryBlobStore::default();
+        let executable = signed_executable();
+        let id = executable.store(&blob_store).await.unwrap();
+        let loaded = Executable::load(&blob_store, &id).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&executable).unwrap()
+        );
+        loaded.unpack(tempdir().unwrap().path()).unwrap();
+
+        assert!(Executable::load(&blob_store, "../secrets").await.is_err());
+    }
+}
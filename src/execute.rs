@@ -2,30 +2,37 @@ use std::{
     cmp::{max, min},
     fs::{self, File},
     io::Write,
-    os::unix::fs::PermissionsExt,
     path::Path,
-    process::Command,
+    sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{anyhow, Context, Result};
-use aws_sdk_s3::{presigning::PresigningConfig, primitives::ByteStream};
+use anyhow::{anyhow, Result};
 use axum::{extract::State, http::StatusCode, Json};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use tempfile::{tempdir, NamedTempFile};
+use tempfile::tempdir;
 use uuid::Uuid;
 
 use crate::{
+    blob_store::BlobStore,
     error::{AppError, HTTPError},
-    run_command::{run_command, CommandOptions},
-    types::Executable,
+    executable::Executable,
+    grader::GRADER_OUTPUT_ENV,
+    languages::registry,
+    run_blocking,
+    run_command::{run_command_streaming, CommandOptions, CommandSpec, OutputCallback},
+    runtime_error::{self, Exception, RuntimeErrorReason},
+    sanitizer::{self, RuntimeErrorDetails},
     AppState,
 };
-use base64::{prelude::BASE64_STANDARD, Engine};
 
 #[derive(Deserialize)]
 pub struct ExecuteRequest {
-    pub executable: Executable,
+    /// Either the executable returned by `/compile`, or the `executable_id` of one stored with
+    /// `store_executable`.
+    pub executable: Option<Executable>,
+    pub executable_id: Option<String>,
     pub options: ExecuteOptions,
 }
 
@@ -41,12 +48,13 @@ pub struct ExecuteOptions {
     pub file_io_name: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub enum Verdict {
     #[serde(rename = "accepted")]
+    #[default]
     Accepted,
+    /// Only used by the `oj` command, which compares the output with the expected answer.
     #[serde(rename = "wrong_answer")]
-    #[allow(dead_code)]
     WrongAnswer,
     #[serde(rename = "time_limit_exceeded")]
     TimeLimitExceeded,
@@ -54,12 +62,6 @@ pub enum Verdict {
     RuntimeError,
 }
 
-impl Default for Verdict {
-    fn default() -> Self {
-        Self::Accepted
-    }
-}
-
 #[derive(Serialize, Default)]
 pub struct ExecuteResponse {
     pub stdout: String,
@@ -67,38 +69,37 @@ pub struct ExecuteResponse {
     /// Only if `file_io_name`.out exists.
     pub file_output: Option<String>,
 
+    /// What the grader wrote to its output file. Only for executables linked against a grader.
+    pub grader_output: Option<String>,
+
     pub stderr: String,
-    pub wall_time: String, // time format is 0:00.00
+    pub wall_time: String, // time format is 0.00, in seconds
     pub memory_usage: String,
 
     /// The underlying raw wait status. Note that this is different from an exit status.
     pub exit_code: i32,
+    /// The exit status, if the process exited normally instead of being killed by a signal.
+    pub exit_status: Option<i32>,
     pub exit_signal: Option<String>,
 
     pub verdict: Verdict,
 
+    /// Why the program crashed. Only if the verdict is a runtime error.
+    pub runtime_error_reason: Option<RuntimeErrorReason>,
+
+    /// The uncaught Java or Python exception, if the program crashed with one.
+    pub exception: Option<Exception>,
+
+    /// Parsed from `stderr` if the program was compiled with `-fsanitize` and the sanitizer
+    /// reported an error.
+    pub runtime_error_details: Option<RuntimeErrorDetails>,
+
     /// If the output is too large, this will be Some(output_url).
-    /// The output URL is a presigned S3 URL that contians the full output.
+    /// The output URL is a presigned URL that contians the full output. It is None if the
+    /// storage backend doesn't support presigned URLs.
     pub full_output_url: Option<String>,
 }
 
-fn extract_zip(dir: &Path, base64_zip: &str) -> Result<()> {
-    let mut tmp_file = NamedTempFile::new()?;
-    tmp_file.write_all(&BASE64_STANDARD.decode(base64_zip)?)?;
-    if !Command::new("tar")
-        .arg("xf")
-        .arg(tmp_file.path().to_str().unwrap())
-        .arg("-C")
-        .arg(dir.to_str().unwrap())
-        .status()?
-        .success()
-    {
-        Err(anyhow!("Failed to extract base64 .tar.gz file"))
-    } else {
-        Ok(())
-    }
-}
-
 fn truncate_if_needed(mut str: String, max_len: usize) -> String {
     if str.len() > max_len {
         // Note: This could panic if truncating multi-byte characters!
@@ -138,33 +139,57 @@ fn truncate_response(mut response: ExecuteResponse) -> ExecuteResponse {
 
 pub async fn execute(
     payload: ExecuteRequest,
-    s3_client: aws_sdk_s3::Client,
+    blob_store: Arc<dyn BlobStore>,
 ) -> Result<ExecuteResponse> {
-    let tmp_dir = tempdir()?;
+    let executable = match (payload.executable, payload.executable_id) {
+        (Some(executable), _) => {
+            executable.verify()?;
+            executable
+        }
+        (None, Some(executable_id)) => {
+            Executable::load(blob_store.as_ref(), &executable_id).await?
+        }
+        (None, None) => {
+            return Err(HTTPError(
+                StatusCode::BAD_REQUEST,
+                "Either executable or executable_id must be provided".to_string(),
+            )
+            .into())
+        }
+    };
+    run_executable(&executable, payload.options, blob_store, None).await
+}
 
-    extract_zip(tmp_dir.path(), &payload.executable.files)?;
+/// Like [`execute`], but for an executable that this process compiled itself, so its signature
+/// isn't checked. Also passes the program's output to `on_output` as it runs.
+pub async fn run_executable(
+    executable: &Executable,
+    options: ExecuteOptions,
+    blob_store: Arc<dyn BlobStore>,
+    on_output: Option<OutputCallback>,
+) -> Result<ExecuteResponse> {
+    let tmp_dir = tempdir()?;
+    executable.unpack(tmp_dir.path())?;
 
-    let stdin = if let Some(stdin_id) = payload.options.stdin_id {
+    let stdin = if let Some(stdin_id) = options.stdin_id {
         if Uuid::parse_str(&stdin_id).is_err() {
             return Err(HTTPError(StatusCode::BAD_REQUEST, "Invalid stdin_id".to_string()).into());
         }
 
-        let object = s3_client
-            .get_object()
-            .bucket("online-judge-rust-data")
-            .key(format!("inputs/{stdin_id}.txt"))
-            .send()
-            .await?;
-
-        object.body.collect().await.map(|data| data.into_bytes())?
+        blob_store
+            .get(&format!("inputs/{stdin_id}.txt"))
+            .await?
+            .ok_or_else(|| {
+                HTTPError(StatusCode::NOT_FOUND, format!("Input {stdin_id} not found"))
+            })?
     } else {
-        Bytes::from(payload.options.stdin.ok_or(HTTPError(
+        Bytes::from(options.stdin.ok_or(HTTPError(
             StatusCode::BAD_REQUEST,
             "Either stdin or stdin_id must be provided".to_string(),
         ))?)
     };
 
-    if let Some(ref name) = payload.options.file_io_name {
+    if let Some(ref name) = options.file_io_name {
         if !name.chars().all(|c| c.is_ascii_alphanumeric()) {
             return Err(anyhow!(
                 "Invalid file I/O name. It must be alphanumeric, like \"cowdating\"."
@@ -174,32 +199,42 @@ pub async fn execute(
         stdin_file.write_all(&stdin)?;
     }
 
+    let time_multiplier = match &executable.manifest.language {
+        Some(language) => registry().get(language)?.time_multiplier,
+        None => 1.0,
+    };
+    // The grader output file is kept outside of the working directory.
+    let grader_output_dir = tempdir()?;
+    let grader_output_path = grader_output_dir.path().join("grader.out");
+    let mut env = Vec::new();
+    if executable.manifest.grader_id.is_some() {
+        env.push((
+            GRADER_OUTPUT_ENV.to_owned(),
+            grader_output_path.to_str().unwrap().to_owned(),
+        ));
+    }
+
     let command_options = CommandOptions {
         stdin,
-        timeout_ms: payload.options.timeout_ms,
+        timeout_ms: (options.timeout_ms as f64 * time_multiplier) as u32,
+        memory_limit_mb: None,
     };
+    let mut command =
+        CommandSpec::from_argv(&executable.manifest.run_command, tmp_dir.path().to_owned())
+            .ok_or_else(|| HTTPError(StatusCode::BAD_REQUEST, "Empty run_command".to_owned()))?;
+    command.env = env;
 
-    // Run the command in a file to get messages like
-    // ./run: line 1:   308 Segmentation fault      ./prog
-    // I don't know why we don't get these messages normally.
-    let mut run_file = File::create(tmp_dir.path().join("run"))?;
-    run_file.write_all(payload.executable.run_command.as_bytes())?;
-    let mut run_file_permissions = run_file.metadata()?.permissions();
-    run_file_permissions.set_mode(0o755);
-    run_file.set_permissions(run_file_permissions)?;
-    drop(run_file);
-
-    let command_output = run_command("./run", tmp_dir.path(), command_options)?;
-
-    let verdict = match command_output.exit_code {
-        // timeout exits with status 124. command_output.exit_code is a wait status.
-        // exit status 124 corresponds to wait status (124 << 8).
-        31744 => Verdict::TimeLimitExceeded,
-        0 => Verdict::Accepted,
-        _ => Verdict::RuntimeError,
+    let command_output = run_command_streaming(&command, command_options, on_output)?;
+
+    let verdict = if command_output.timed_out {
+        Verdict::TimeLimitExceeded
+    } else if command_output.exit_code == 0 {
+        Verdict::Accepted
+    } else {
+        Verdict::RuntimeError
     };
 
-    let file_output = if let Some(name) = payload.options.file_io_name {
+    let file_output = if let Some(name) = options.file_io_name {
         let output_file_path = tmp_dir.path().join(name).with_extension("out");
         if Path::exists(&output_file_path) {
             Some(String::from_utf8_lossy(&fs::read(output_file_path)?).into_owned())
@@ -210,40 +245,62 @@ pub async fn execute(
         None
     };
 
+    let grader_output = if executable.manifest.grader_id.is_some() {
+        fs::read(&grader_output_path)
+            .ok()
+            .map(|output| String::from_utf8_lossy(&output).into_owned())
+    } else {
+        None
+    };
+
+    let runtime_error_details = sanitizer::parse(&command_output.stderr);
+    let (runtime_error_reason, exception) = match verdict {
+        Verdict::RuntimeError => (
+            runtime_error::classify(
+                command_output.exit_code,
+                &command_output.stderr,
+                executable.manifest.runs_in_shell(),
+            ),
+            runtime_error::parse_exception(
+                &command_output.stderr,
+                &executable.manifest.source_files,
+            ),
+        ),
+        _ => (None, None),
+    };
+
     let mut response = ExecuteResponse {
         stdout: command_output.stdout,
         file_output,
+        grader_output,
         stderr: command_output.stderr,
         wall_time: command_output.wall_time,
         memory_usage: command_output.memory_usage,
         exit_code: command_output.exit_code,
+        exit_status: runtime_error::exit_status(command_output.exit_code),
         exit_signal: command_output.exit_signal,
         verdict,
+        runtime_error_reason,
+        exception,
+        runtime_error_details,
         full_output_url: None,
     };
 
     let json_str = serde_json::to_string(&response)?;
     if json_str.len() > 5_500_000 {
-        let id = Uuid::new_v4();
-        s3_client
-            .put_object()
-            .bucket("online-judge-rust-data")
-            .key(format!("outputs/{id}.json"))
-            .body(ByteStream::from(Bytes::from(json_str)))
-            .content_type("application/json")
-            .send()
-            .await?;
-
-        let presigned_url = s3_client
-            .get_object()
-            .bucket("online-judge-rust-data")
-            .key(format!("outputs/{id}.json"))
-            .presigned(PresigningConfig::expires_in(
-                std::time::Duration::from_secs(60 * 60 * 24),
-            )?)
+        let key = format!("outputs/{}.json", Uuid::new_v4());
+        // Without presigned URLs there is no way for the client to fetch the full output, so it
+        // isn't stored and the response is only truncated.
+        let presigned_url = blob_store
+            .presigned_get_url(&key, Duration::from_secs(60 * 60 * 24))
             .await?;
+        if presigned_url.is_some() {
+            blob_store
+                .put(&key, Bytes::from(json_str), "application/json")
+                .await?;
+        }
 
-        response.full_output_url = Some(presigned_url.uri().to_string());
+        response.full_output_url = presigned_url;
         return Ok(truncate_response(response));
     }
 
@@ -254,5 +311,7 @@ pub async fn execute_handler(
     State(state): State<AppState>,
     Json(payload): Json<ExecuteRequest>,
 ) -> Result<Json<ExecuteResponse>, AppError> {
-    Ok(Json(execute(payload, state.s3_client).await?))
+    Ok(Json(
+        run_blocking(execute(payload, state.blob_store)).await?,
+    ))
 }
@@ -0,0 +1,58 @@
+//! IOI-style graders.
+//!
+//! For some problems, contestants only submit a function implementation, which is linked against a
+//! trusted grader that reads the input and calls it. The grader's source and headers are stored
+//! with the problem and never returned to the user; only the compiled executable is. Its sources
+//! and headers are written to separate directories that the user's files can't replace, only the
+//! headers can be included by the user's code, and both paths are scrubbed from the compiler
+//! output. `-D` and `-U` are rejected, since compiler options also apply to the grader's sources.
+//!
+//! The grader writes its result to the file named by the `GRADER_OUTPUT_FILE` environment
+//! variable, which is returned separately as `grader_output`, rather than to stdout. The user's
+//! code runs in the same process, so this only keeps it from faking the result if the grader opens
+//! the file and calls `unsetenv("GRADER_OUTPUT_FILE")` before calling any user code. Even then, the
+//! user's global constructors run before the grader's `main`, and the user's code can find the
+//! open file through `/proc/self/fd`, so graders for problems where that matters must validate
+//! their own output.
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    blob_store::BlobStore,
+    error::HTTPError,
+    types::{Language, SourceFile},
+};
+
+pub const GRADER_OUTPUT_ENV: &str = "GRADER_OUTPUT_FILE";
+
+/// Stored as JSON at `graders/{grader_id}.json` in the blob store.
+#[derive(Deserialize)]
+pub struct Grader {
+    pub language: Language,
+    /// The grader's source and headers. Sources are compiled along with the user's, and headers
+    /// can be included by the user's code.
+    pub files: Vec<SourceFile>,
+}
+
+pub async fn load_grader(blob_store: &dyn BlobStore, grader_id: &str) -> Result<Grader> {
+    if grader_id.is_empty()
+        || !grader_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(HTTPError(StatusCode::BAD_REQUEST, "Invalid grader_id".to_string()).into());
+    }
+
+    let data = blob_store
+        .get(&format!("graders/{grader_id}.json"))
+        .await?
+        .ok_or_else(|| {
+            HTTPError(
+                StatusCode::NOT_FOUND,
+                format!("Grader {grader_id} not found"),
+            )
+        })?;
+    Ok(serde_json::from_slice(&data)?)
+}
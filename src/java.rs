@@ -0,0 +1,295 @@
+//! Finds the class to run in a Java source file.
+//!
+//! javac requires a file to be named after its public class, and `java` needs the (package
+//! qualified) name of the class with `main`. A regex over the raw source gets confused by
+//! comments, strings and nested classes, so this does a small scan that understands enough Java
+//! to skip those.
+
+use regex::Regex;
+
+/// Where a Java source file should be written and which class should be run.
+#[derive(Debug, PartialEq)]
+pub struct JavaEntryPoint {
+    /// Path of the source file relative to the source root, like "com/example/Main.java".
+    pub source_path: String,
+    /// Fully qualified name of the class containing `main`, like "com.example.Main".
+    pub main_class: String,
+}
+
+struct TopLevelClass {
+    name: String,
+    is_public: bool,
+    has_main: bool,
+}
+
+/// Splits Java source into identifiers and single-character symbols, skipping whitespace,
+/// comments, and string and character literals.
+fn tokenize(source: &str) -> Vec<String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' && next == Some('"') && chars.get(i + 2) == Some(&'"') {
+            // Text block
+            i += 3;
+            while i < chars.len() && !chars[i..].starts_with(&['"', '"', '"']) {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i += 3;
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            tokens.push(c.to_string());
+            i += 1;
+        }
+    }
+    tokens
+}
+
+/// Returns the package and the top level classes declared in `source`.
+fn scan(source: &str) -> (Option<String>, Vec<TopLevelClass>) {
+    let tokens = tokenize(source);
+    let mut package = None;
+    let mut classes: Vec<TopLevelClass> = Vec::new();
+    let mut depth = 0;
+    // Tokens of the declaration currently being read at depth 0 (the class) or depth 1 (a member
+    // of the class).
+    let mut declaration: Vec<&str> = Vec::new();
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i].as_str();
+        match token {
+            "{" => {
+                depth += 1;
+                declaration.clear();
+            }
+            "}" => {
+                depth -= 1;
+                declaration.clear();
+            }
+            ";" => declaration.clear(),
+            "package" if depth == 0 && classes.is_empty() => {
+                let mut name = String::new();
+                i += 1;
+                while i < tokens.len() && tokens[i] != ";" {
+                    name += &tokens[i];
+                    i += 1;
+                }
+                package = Some(name);
+            }
+            "class" | "interface" | "enum" | "record"
+                if depth == 0 && declaration.last() != Some(&".") =>
+            {
+                if let Some(name) = tokens.get(i + 1) {
+                    classes.push(TopLevelClass {
+                        name: name.clone(),
+                        is_public: declaration.contains(&"public"),
+                        has_main: false,
+                    });
+                }
+                declaration.push(token);
+            }
+            "main" if depth == 1 && tokens.get(i + 1).map(String::as_str) == Some("(") => {
+                let parameters = tokens[i + 2..]
+                    .iter()
+                    .take_while(|token| *token != ")")
+                    .map(String::as_str)
+                    .collect::<Vec<_>>();
+                if declaration.last() == Some(&"void")
+                    && declaration.contains(&"public")
+                    && declaration.contains(&"static")
+                    && parameters.contains(&"String")
+                {
+                    if let Some(class) = classes.last_mut() {
+                        class.has_main = true;
+                    }
+                }
+                declaration.push(token);
+            }
+            _ => {
+                if depth <= 1 {
+                    declaration.push(token);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    (package, classes)
+}
+
+/// Picks the source path and main class for `source`, or returns a message explaining why it
+/// couldn't.
+pub fn find_entry_point(source: &str) -> Result<JavaEntryPoint, String> {
+    let (package, classes) = scan(source);
+
+    // The package becomes part of the path the source is written to, so it must be a plain
+    // dotted name.
+    if let Some(package) = &package {
+        if !Regex::new(r"^\w+(\.\w+)*$").unwrap().is_match(package) {
+            return Err(format!("Invalid package name {package}"));
+        }
+    }
+
+    let public_classes: Vec<&TopLevelClass> = classes.iter().filter(|c| c.is_public).collect();
+    if public_classes.len() > 1 {
+        return Err(format!(
+            "Only one top level class can be public, but found {}",
+            public_classes
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    let main_classes: Vec<&TopLevelClass> = classes.iter().filter(|c| c.has_main).collect();
+    let main_class = match main_classes.as_slice() {
+        [] => {
+            return Err(
+                "Couldn't find a top level class with public static void main(String[] args)"
+                    .to_string(),
+            )
+        }
+        [class] => class,
+        _ => match main_classes.iter().find(|c| c.is_public) {
+            Some(class) => class,
+            None => {
+                return Err(format!(
+                    "Found public static void main(String[] args) in more than one class ({}). \
+                     Make the class that should be run public.",
+                    main_classes
+                        .iter()
+                        .map(|c| c.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        },
+    };
+
+    // javac requires the file to be named after the public class, even if it isn't the one
+    // with main.
+    let file_class = public_classes.first().unwrap_or(main_class);
+    let package_prefix = package
+        .as_ref()
+        .map(|p| format!("{p}."))
+        .unwrap_or_default();
+    let package_dir = package
+        .as_ref()
+        .map(|p| format!("{}/", p.replace('.', "/")))
+        .unwrap_or_default();
+
+    Ok(JavaEntryPoint {
+        source_path: format!("{package_dir}{}.java", file_class.name),
+        main_class: format!("{package_prefix}{}", main_class.name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_point(source: &str) -> JavaEntryPoint {
+        find_entry_point(source).unwrap()
+    }
+
+    #[test]
+    fn finds_main_class() {
+        assert_eq!(
+            entry_point("public class Main { public static void main(String[] args) {} }"),
+            JavaEntryPoint {
+                source_path: "Main.java".to_owned(),
+                main_class: "Main".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn uses_package() {
+        assert_eq!(
+            entry_point(
+                "package com.example; public class Main { public static void main(String[] a) {} }"
+            ),
+            JavaEntryPoint {
+                source_path: "com/example/Main.java".to_owned(),
+                main_class: "com.example.Main".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_packages_that_are_not_names() {
+        for package in ["/tmp/evil", "..", "a..b", ".a", "a/b"] {
+            let source = format!(
+                "package {package}; public class Main {{ public static void main(String[] a) {{}} }}"
+            );
+            assert!(find_entry_point(&source).is_err(), "{package}");
+        }
+    }
+
+    #[test]
+    fn ignores_comments_and_strings() {
+        let source = r#"
+            // public class Fake { public static void main(String[] args) {} }
+            /* class Other { public static void main(String[] args) {} } */
+            class Solution {
+                static String s = "class Wrong { public static void main(String[] a) {} }";
+                public static void main(String[] args) {}
+            }
+        "#;
+        assert_eq!(entry_point(source).main_class, "Solution");
+    }
+
+    #[test]
+    fn file_is_named_after_public_class() {
+        let source = "class Helper { public static void main(String[] a) {} } public class Util {}";
+        assert_eq!(
+            entry_point(source),
+            JavaEntryPoint {
+                source_path: "Util.java".to_owned(),
+                main_class: "Helper".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_main_in_nested_classes() {
+        let source =
+            "public class Main { static class Inner { public static void main(String[] a) {} } }";
+        assert!(find_entry_point(source).is_err());
+    }
+
+    #[test]
+    fn rejects_several_public_classes() {
+        let source = "public class A { public static void main(String[] a) {} } public class B {}";
+        assert!(find_entry_point(source).is_err());
+    }
+}
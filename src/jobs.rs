@@ -0,0 +1,348 @@
+//! Asynchronous jobs, for batches of tests that take longer than a single request is allowed to.
+//!
+//! `POST /jobs` stores a queued job and returns it immediately. In-process workers compile the
+//! program and run the tests in order, writing the job to `jobs/{id}.json` in the blob store as it
+//! progresses (at most once a second while tests run), so `GET /jobs/{id}` shows partial results
+//! while the job runs. Outputs are truncated more aggressively than by `/execute`, since the whole
+//! job is rewritten each time.
+//!
+//! Workers only run while the process does, so jobs are only supported by the standalone server;
+//! Lambda freezes the process between requests. Jobs that were queued or running when the server
+//! stopped are never finished.
+
+use std::{
+    env,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+use crate::{
+    blob_store::BlobStore,
+    compile::{compile, CompileRequest, CompileStatus},
+    diagnostics::Diagnostic,
+    error::{AppError, HTTPError},
+    executable::now,
+    execute::{run_executable, ExecuteOptions, ExecuteResponse},
+    run_command::CommandOutput,
+    AppState,
+};
+
+/// Limit on the number of tests in one job.
+const MAX_TESTS: usize = 500;
+
+/// Limit on the number of jobs waiting for a worker. Further jobs are rejected with a 503.
+const MAX_QUEUED_JOBS: usize = 1000;
+
+/// Limit on each output of a test, like its stdout, and on the compiler's stdout and stderr.
+const MAX_OUTPUT_BYTES: usize = 64_000;
+
+/// Limit on all of a job's outputs together, which keeps the job's JSON to about this size.
+const MAX_JOB_OUTPUT_BYTES: usize = 5_000_000;
+
+/// Minimum time between saves of a job while its tests run.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Payload for POST /jobs
+#[derive(Deserialize)]
+pub struct JobRequest {
+    pub compile: CompileRequest,
+    /// The program is run once for each test, in order.
+    pub tests: Vec<ExecuteOptions>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for a worker.
+    Queued,
+    Compiling,
+    Running,
+    /// All tests have run, or the program failed to compile.
+    Finished,
+    /// The job couldn't be completed; see `error`.
+    Failed,
+}
+
+/// Response for POST /jobs and GET /jobs/{id}
+#[derive(Serialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// None until the program has been compiled.
+    pub compile_status: Option<CompileStatus>,
+    pub compile: Option<CommandOutput>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub test_count: usize,
+    /// Results of the tests that have run so far, in the order of `tests` in the request. Outputs
+    /// are truncated to [`MAX_OUTPUT_BYTES`] each, and to [`MAX_JOB_OUTPUT_BYTES`] in total.
+    pub results: Vec<ExecuteResponse>,
+    /// Why the job failed, such as an invalid `stdin_id`.
+    pub error: Option<String>,
+    /// Unix time in seconds when the job was submitted.
+    pub created_at: u64,
+}
+
+fn job_key(id: &str) -> String {
+    format!("jobs/{id}.json")
+}
+
+impl Job {
+    async fn save(&self, blob_store: &dyn BlobStore) -> Result<()> {
+        blob_store
+            .put(
+                &job_key(&self.id),
+                Bytes::from(serde_json::to_vec(self)?),
+                "application/json",
+            )
+            .await
+    }
+}
+
+/// Hands submitted jobs to the workers.
+#[derive(Clone)]
+pub struct JobQueue {
+    sender: SyncSender<(Job, JobRequest)>,
+}
+
+/// Number of jobs run at once, from `JOB_WORKERS`. Defaults to the number of CPUs.
+fn worker_count() -> usize {
+    env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|workers| workers.parse().ok())
+        .filter(|workers| *workers > 0)
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()))
+}
+
+/// Starts the workers. Must be called from within the Tokio runtime.
+pub fn start_workers(blob_store: Arc<dyn BlobStore>) -> JobQueue {
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_JOBS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let runtime = Handle::current();
+    for _ in 0..worker_count() {
+        let receiver = receiver.clone();
+        let blob_store = blob_store.clone();
+        let runtime = runtime.clone();
+        // Running programs blocks, so workers get their own threads rather than tying up the
+        // runtime's threads, which serve requests.
+        thread::spawn(move || worker(&receiver, blob_store, &runtime));
+    }
+    JobQueue { sender }
+}
+
+fn worker(
+    receiver: &Mutex<Receiver<(Job, JobRequest)>>,
+    blob_store: Arc<dyn BlobStore>,
+    runtime: &Handle,
+) {
+    loop {
+        // The lock is released as soon as a job is received, so other workers can take the next.
+        let Ok((mut job, request)) = receiver.lock().unwrap().recv() else {
+            return;
+        };
+        let result = runtime.block_on(run_job(&mut job, request, blob_store.clone()));
+        if let Err(err) = result {
+            job.status = JobStatus::Failed;
+            job.error = Some(error_message(&err));
+            if let Err(err) = runtime.block_on(job.save(blob_store.as_ref())) {
+                println!("Warning: Failed to save job {}: {err}", job.id);
+            }
+        }
+    }
+}
+
+/// The message for a failed job's `error`, without the status of an [`HTTPError`].
+fn error_message(err: &anyhow::Error) -> String {
+    match err.downcast_ref::<HTTPError>() {
+        Some(HTTPError(_, message)) => message.clone(),
+        None => err.to_string(),
+    }
+}
+
+async fn run_job(job: &mut Job, request: JobRequest, blob_store: Arc<dyn BlobStore>) -> Result<()> {
+    job.status = JobStatus::Compiling;
+    job.save(blob_store.as_ref()).await?;
+
+    let mut output_budget = MAX_JOB_OUTPUT_BYTES;
+    let mut compile_output = compile(request.compile, blob_store.clone()).await?;
+    truncate_output(
+        &mut compile_output.compile_output.stdout,
+        &mut output_budget,
+    );
+    truncate_output(
+        &mut compile_output.compile_output.stderr,
+        &mut output_budget,
+    );
+    job.compile_status = Some(compile_output.compile_status);
+    job.compile = Some(compile_output.compile_output);
+    job.diagnostics = compile_output.diagnostics;
+    let Some(executable) = compile_output.executable else {
+        job.status = JobStatus::Finished;
+        return job.save(blob_store.as_ref()).await;
+    };
+
+    job.status = JobStatus::Running;
+    job.save(blob_store.as_ref()).await?;
+    let mut last_save = Instant::now();
+    for options in request.tests {
+        let mut result = run_executable(&executable, options, blob_store.clone(), None).await?;
+        truncate_output(&mut result.stdout, &mut output_budget);
+        truncate_output(&mut result.stderr, &mut output_budget);
+        for output in [&mut result.file_output, &mut result.grader_output]
+            .into_iter()
+            .flatten()
+        {
+            truncate_output(output, &mut output_budget);
+        }
+        job.results.push(result);
+        if last_save.elapsed() >= SAVE_INTERVAL {
+            job.save(blob_store.as_ref()).await?;
+            last_save = Instant::now();
+        }
+    }
+
+    job.status = JobStatus::Finished;
+    job.save(blob_store.as_ref()).await
+}
+
+/// Truncates `output` to [`MAX_OUTPUT_BYTES`] or what is left of `budget`, whichever is smaller,
+/// and subtracts its length from `budget`.
+fn truncate_output(output: &mut String, budget: &mut usize) {
+    let max_len = MAX_OUTPUT_BYTES.min(*budget);
+    if output.len() > max_len {
+        let mut len = max_len;
+        while !output.is_char_boundary(len) {
+            len -= 1;
+        }
+        output.truncate(len);
+        output.push_str("\n[Truncated]");
+    }
+    *budget = budget.saturating_sub(output.len());
+}
+
+/// Jobs are returned exactly as they were saved.
+fn job_response(data: Bytes) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], data)
+}
+
+pub async fn create_job_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<JobRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(jobs) = state.jobs else {
+        return Err(HTTPError(
+            StatusCode::NOT_IMPLEMENTED,
+            "Jobs are only supported by the standalone server".to_owned(),
+        )
+        .into());
+    };
+    if payload.tests.is_empty() || payload.tests.len() > MAX_TESTS {
+        return Err(HTTPError(
+            StatusCode::BAD_REQUEST,
+            format!("A job must have between 1 and {MAX_TESTS} tests"),
+        )
+        .into());
+    }
+
+    let job = Job {
+        id: Uuid::new_v4().to_string(),
+        status: JobStatus::Queued,
+        compile_status: None,
+        compile: None,
+        diagnostics: Vec::new(),
+        test_count: payload.tests.len(),
+        results: Vec::new(),
+        error: None,
+        created_at: now(),
+    };
+    let data = Bytes::from(serde_json::to_vec(&job)?);
+    // Save the job before queueing it, so that it can be polled as soon as its id is returned.
+    state
+        .blob_store
+        .put(&job_key(&job.id), data.clone(), "application/json")
+        .await?;
+    let (mut job, error) = match jobs.sender.try_send((job, payload)) {
+        Ok(()) => return Ok((StatusCode::ACCEPTED, job_response(data))),
+        Err(TrySendError::Full((job, _))) => (
+            job,
+            HTTPError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many jobs are queued. Try again later.".to_owned(),
+            )
+            .into(),
+        ),
+        Err(TrySendError::Disconnected((job, _))) => (job, anyhow!("Job workers have stopped")),
+    };
+    // Otherwise the saved job would stay queued forever.
+    job.status = JobStatus::Failed;
+    job.error = Some(error_message(&error));
+    job.save(state.blob_store.as_ref()).await?;
+    Err(error.into())
+}
+
+pub async fn get_job_handler(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    if Uuid::parse_str(&job_id).is_err() {
+        return Err(HTTPError(StatusCode::BAD_REQUEST, "Invalid job id".to_string()).into());
+    }
+    let data = state
+        .blob_store
+        .get(&job_key(&job_id))
+        .await?
+        .ok_or_else(|| HTTPError(StatusCode::NOT_FOUND, format!("Job {job_id} not found")))?;
+    Ok(job_response(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_long_outputs() {
+        let mut budget = MAX_JOB_OUTPUT_BYTES;
+        let mut output = "x".repeat(MAX_OUTPUT_BYTES + 1);
+        truncate_output(&mut output, &mut budget);
+        assert!(output.ends_with("\n[Truncated]"));
+        assert_eq!(budget, MAX_JOB_OUTPUT_BYTES - output.len());
+    }
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        let mut budget = 2;
+        let mut output = "é".repeat(2);
+        truncate_output(&mut output, &mut budget);
+        assert_eq!(output, "é\n[Truncated]");
+    }
+
+    #[test]
+    fn stops_at_the_job_budget() {
+        let mut budget = 10;
+        let mut output = "short".to_owned();
+        truncate_output(&mut output, &mut budget);
+        assert_eq!((output.as_str(), budget), ("short", 5));
+
+        let mut output = "too long".to_owned();
+        truncate_output(&mut output, &mut budget);
+        assert_eq!((output.as_str(), budget), ("too l\n[Truncated]", 0));
+        let mut output = "x".to_owned();
+        truncate_output(&mut output, &mut budget);
+        assert_eq!(output, "\n[Truncated]");
+    }
+}
@@ -0,0 +1,387 @@
+//! Registry of supported languages.
+//!
+//! Each language is described by a [`LanguageSpec`] instead of being hardcoded into `compile`. The
+//! specs are read from `languages.json` (embedded at build time), or from the file at
+//! `$LANGUAGES_CONFIG` if it is set, so new runtimes can be added through deployment config.
+
+use std::{env, fs, process::Command, sync::OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::StatusCode;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{diagnostics::DiagnosticsFormat, error::HTTPError, types::Language};
+
+const DEFAULT_CONFIG: &str = include_str!("../languages.json");
+
+/// How the name of the source file is picked.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFilenameRule {
+    /// Always use this filename, like "program.cpp".
+    Fixed(String),
+    /// Java requires the file to be named after its public class, like "Main.java", and placed
+    /// in a directory matching its package. See [`crate::java`].
+    JavaMainClass,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LanguageSpec {
+    /// The value of `language` in requests, such as "cpp".
+    pub id: Language,
+
+    /// Human-readable name, such as "C++".
+    pub display_name: String,
+
+    pub source_filename: SourceFilenameRule,
+
+    /// Program and arguments that compile the program, run without a shell. It is run from the
+    /// directory containing the source file, and should write everything needed to run the
+    /// program to `{out_dir}`.
+    ///
+    /// Supports the placeholders `{source}` (path of the main source file), `{program}` (main
+    /// source filename without its extension), `{main_class}` (the class to run, for Java) and
+    /// `{out_dir}` anywhere in an argument. The list placeholders `{sources}` (paths of all
+    /// submitted files with one of `source_extensions`) and `{options}` (compiler options) must be
+    /// a whole argument, and expand to any number of arguments. Languages with toolchains can also
+    /// use `{compiler}` and the list placeholder `{pch_flags}` (flags to use a precompiled header).
+    /// Languages that support graders also get the list placeholder `{grader_flags}`, and the
+    /// grader's source files are added to `{sources}`.
+    pub compile_command: Vec<String>,
+
+    /// Extensions of submitted files that are substituted for `{sources}`, like "cpp". Other
+    /// files, like headers, are only written next to the main source file.
+    #[serde(default)]
+    pub source_extensions: Vec<String>,
+
+    /// Program and arguments that run the program from the extracted executable. Supports the
+    /// same placeholders as `compile_command`, except `{out_dir}`.
+    pub run_command: Vec<String>,
+
+    /// Used when a request doesn't specify `compiler_options`.
+    #[serde(default)]
+    pub default_compiler_options: String,
+
+    /// Regexes that every compiler option must fully match. If unset, any option is allowed.
+    pub allowed_flags: Option<Vec<String>>,
+
+    /// Regexes for compiler options that are rejected even if `allowed_flags` matches them, such
+    /// as flags that read or write files outside the source directory.
+    #[serde(default)]
+    pub denied_flags: Vec<String>,
+
+    /// How to parse compiler output into `diagnostics`. If unset, no diagnostics are reported.
+    pub diagnostics_format: Option<DiagnosticsFormat>,
+
+    /// Time limits are multiplied by this, for languages that are inherently slower.
+    #[serde(default = "default_multiplier")]
+    pub time_multiplier: f64,
+
+    /// Whether problem graders can be linked in. Only languages whose executable doesn't contain
+    /// the source can support graders, since the grader's source must stay hidden. This rules
+    /// out Java, whose class files are easily decompiled.
+    #[serde(default)]
+    pub supports_graders: bool,
+
+    /// Substituted for `{grader_flags}` in the compile command when a grader is linked in, so that
+    /// the user's code can include the grader's headers, like `["-iquote", "{grader_dir}"]`.
+    /// `{grader_dir}` is the directory containing the grader's headers, but not its sources.
+    #[serde(default)]
+    pub grader_flags: Vec<String>,
+
+    /// Compilers that a request can choose between, such as GCC and Clang for C++. The first one
+    /// is the default. Empty for languages with only one way to compile.
+    #[serde(default)]
+    pub toolchains: Vec<ToolchainSpec>,
+
+    /// Shell command whose first line of output is reported as the compiler / runtime version,
+    /// like "python3.12 --version". Languages with toolchains report a version per toolchain.
+    pub version_command: Option<String>,
+
+    #[serde(skip)]
+    version: OnceLock<Option<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ToolchainSpec {
+    /// The value of `toolchain` in requests, such as "gcc".
+    pub id: String,
+
+    pub display_name: String,
+
+    /// Substituted for `{compiler}` in the compile command, like "clang++".
+    pub compiler: String,
+
+    pub version_command: Option<String>,
+
+    /// If set, `#include <bits/stdc++.h>` uses a precompiled header built by this toolchain.
+    pub precompiled_header: Option<PrecompiledHeaderSpec>,
+
+    /// Compiler options whose precompiled headers are built in the background after a cold
+    /// start, like "-std=c++23 -O2". See [`crate::pch::warm`].
+    #[serde(default)]
+    pub warm_pch_options: Vec<String>,
+
+    #[serde(skip)]
+    version: OnceLock<Option<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PrecompiledHeaderSpec {
+    pub style: PrecompiledHeaderStyle,
+
+    /// Path to the header to precompile, like
+    /// "/usr/include/c++/11/x86_64-amazon-linux/bits/stdc++.h".
+    pub header: String,
+}
+
+/// GCC and Clang find precompiled headers differently.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrecompiledHeaderStyle {
+    /// GCC looks for `bits/stdc++.h.gch` next to `bits/stdc++.h` in every include directory, so
+    /// the precompiled header is picked up with `-I`.
+    Gcc,
+    /// Clang has to be told about the precompiled header with `-include-pch`.
+    Clang,
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+pub struct LanguageRegistry {
+    languages: Vec<LanguageSpec>,
+}
+
+impl LanguageRegistry {
+    fn from_json(json: &str) -> Result<Self> {
+        let languages: Vec<LanguageSpec> = serde_json::from_str(json)?;
+        for spec in &languages {
+            for pattern in spec.allowed_flags.iter().flatten() {
+                Regex::new(pattern).with_context(|| {
+                    format!("Invalid allowed flag pattern for {}: {pattern}", spec.id.0)
+                })?;
+            }
+            for pattern in &spec.denied_flags {
+                Regex::new(pattern).with_context(|| {
+                    format!("Invalid denied flag pattern for {}: {pattern}", spec.id.0)
+                })?;
+            }
+        }
+        Ok(Self { languages })
+    }
+
+    fn load() -> Result<Self> {
+        match env::var("LANGUAGES_CONFIG") {
+            Ok(path) => Self::from_json(
+                &fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read language config {path}"))?,
+            )
+            .with_context(|| format!("Failed to parse language config {path}")),
+            Err(_) => Self::from_json(DEFAULT_CONFIG).context("Failed to parse languages.json"),
+        }
+    }
+
+    pub fn all(&self) -> &[LanguageSpec] {
+        &self.languages
+    }
+
+    pub fn get(&self, language: &Language) -> Result<&LanguageSpec> {
+        self.languages
+            .iter()
+            .find(|spec| spec.id == *language)
+            .ok_or_else(|| {
+                HTTPError(
+                    StatusCode::BAD_REQUEST,
+                    format!("Unsupported language: {}", language.0),
+                )
+                .into()
+            })
+    }
+}
+
+static REGISTRY: OnceLock<LanguageRegistry> = OnceLock::new();
+
+/// Loads the language config. Called on startup so that a bad config fails fast instead of on
+/// the first request.
+pub fn init() -> Result<()> {
+    let registry = LanguageRegistry::load()?;
+    REGISTRY
+        .set(registry)
+        .map_err(|_| anyhow!("Language registry was already initialized"))
+}
+
+pub fn registry() -> &'static LanguageRegistry {
+    REGISTRY.get_or_init(|| LanguageRegistry::load().expect("Failed to load language config"))
+}
+
+/// Runs a version command and returns the first line of its output.
+fn probe_version(command: &str) -> Option<String> {
+    let output = Command::new("sh").arg("-c").arg(command).output().ok()?;
+    if !output.status.success() {
+        println!("Warning: Version command {command} failed");
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_owned)
+}
+
+impl ToolchainSpec {
+    /// The compiler version, probed the first time it is needed.
+    pub fn version(&self) -> Option<&str> {
+        self.version
+            .get_or_init(|| probe_version(self.version_command.as_ref()?))
+            .as_deref()
+    }
+}
+
+impl LanguageSpec {
+    /// The compiler / runtime version, probed the first time it is needed. None if the language
+    /// has no `version_command` or the command failed.
+    pub fn version(&self) -> Option<&str> {
+        self.version
+            .get_or_init(|| probe_version(self.version_command.as_ref()?))
+            .as_deref()
+    }
+
+    /// Picks the requested toolchain, or the default one if none was requested.
+    pub fn toolchain(&self, toolchain: Option<&str>) -> Result<Option<&ToolchainSpec>> {
+        match toolchain {
+            None => Ok(self.toolchains.first()),
+            Some(id) => self
+                .toolchains
+                .iter()
+                .find(|toolchain| toolchain.id == id)
+                .map(Some)
+                .ok_or_else(|| {
+                    HTTPError(
+                        StatusCode::BAD_REQUEST,
+                        format!("Unsupported toolchain for {}: {id}", self.id.0),
+                    )
+                    .into()
+                }),
+        }
+    }
+
+    /// Splits `compiler_options` into arguments like a shell would, and checks each one against
+    /// `denied_flags` and `allowed_flags`.
+    pub fn parse_compiler_options(&self, compiler_options: &str) -> Result<Vec<String>> {
+        let options = shell_words::split(compiler_options).map_err(|err| {
+            HTTPError(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid compiler options: {err}"),
+            )
+        })?;
+
+        let full_match = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(&format!("^(?:{pattern})$")))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let denied = full_match(&self.denied_flags)?;
+        let allowed = self.allowed_flags.as_deref().map(full_match).transpose()?;
+        for option in &options {
+            let is_allowed = !denied.iter().any(|re| re.is_match(option))
+                && allowed
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.iter().any(|re| re.is_match(option)));
+            if !is_allowed {
+                return Err(HTTPError(
+                    StatusCode::BAD_REQUEST,
+                    format!("Compiler option {option} is not allowed"),
+                )
+                .into());
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Fills in the `{name}` placeholders of a command template.
+pub fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_owned(), |command, (name, value)| {
+            command.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+/// Fills in the placeholders of a command given as an argument list. An argument that is exactly
+/// one of the `lists` placeholders, like `{sources}`, is replaced by all of its values.
+pub fn fill_argv(
+    template: &[String],
+    values: &[(&str, &str)],
+    lists: &[(&str, &[String])],
+) -> Vec<String> {
+    template
+        .iter()
+        .flat_map(
+            |arg| match lists.iter().find(|(name, _)| *arg == format!("{{{name}}}")) {
+                Some((_, list)) => list.to_vec(),
+                None => vec![fill_template(arg, values)],
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_cpp_options(options: &str) -> Result<Vec<String>> {
+        let registry = LanguageRegistry::from_json(DEFAULT_CONFIG).unwrap();
+        registry
+            .get(&Language("cpp".to_owned()))
+            .unwrap()
+            .parse_compiler_options(options)
+    }
+
+    #[test]
+    fn accepts_common_options() {
+        let options = "-std=c++17 -O2 -Wall -fsanitize=address,undefined -D_GLIBCXX_DEBUG";
+        assert_eq!(
+            parse_cpp_options(options).unwrap(),
+            shell_words::split(options).unwrap()
+        );
+        let registry = LanguageRegistry::from_json(DEFAULT_CONFIG).unwrap();
+        for spec in registry.all() {
+            spec.parse_compiler_options(&spec.default_compiler_options)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_injection() {
+        for options in [
+            "-O2; rm -rf /tmp/*",
+            "-O2 && rm -rf /tmp/*",
+            "$(rm -rf /tmp/*)",
+            "-o /tmp/precompiled-headers/gcc/bits/stdc++.h.gch",
+            "-o/tmp/precompiled-headers/gcc/bits/stdc++.h.gch",
+            "-I/tmp",
+            "-include /etc/passwd",
+            "@/tmp/options",
+            "-Wl,--wrap=main",
+            "-fplugin=/tmp/plugin.so",
+            "-fdump-tree-all",
+            "-B/tmp",
+            "--sysroot=/tmp",
+        ] {
+            assert!(
+                parse_cpp_options(options).is_err(),
+                "{options} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unbalanced_quotes() {
+        assert!(parse_cpp_options("-DX='a").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! A serverless online judge. The server runs as a Lambda function or a standalone HTTP server
+//! (see `main.rs`), and the `oj` binary runs the same judge logic from the command line.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use blob_store::BlobStore;
+use error::{AppError, HTTPError};
+use jobs::{create_job_handler, get_job_handler, JobQueue};
+
+pub mod archive;
+pub mod blob_store;
+pub mod compile;
+pub mod compile_and_execute;
+pub mod compile_cache;
+pub mod diagnostics;
+pub mod error;
+pub mod executable;
+pub mod execute;
+pub mod grader;
+pub mod java;
+pub mod jobs;
+pub mod languages;
+pub mod pch;
+pub mod run_command;
+pub mod runtime_error;
+pub mod sanitizer;
+pub mod types;
+
+use compile::compile_handler;
+use compile_and_execute::{compile_and_execute_handler, compile_and_execute_stream_handler};
+use execute::execute_handler;
+use serde::Serialize;
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub blob_store: Arc<dyn BlobStore>,
+    /// None when running on Lambda, which can't run jobs in the background.
+    pub jobs: Option<JobQueue>,
+}
+
+/// Runs `future` on a blocking thread. Compiling and running programs block the thread until the
+/// process exits, which would otherwise stall one of the runtime's threads that serve requests.
+pub(crate) async fn run_blocking<T: Send + 'static>(
+    future: impl Future<Output = Result<T>> + Send + 'static,
+) -> Result<T> {
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || runtime.block_on(future)).await?
+}
+
+async fn index_page() -> &'static str {
+    "Serverless Online Judge (Rust)"
+}
+
+#[derive(Serialize)]
+struct LargeInputResponse {
+    presigned_url: String,
+    input_id: String,
+}
+
+async fn large_input_handler(
+    State(state): State<AppState>,
+) -> Result<Json<LargeInputResponse>, AppError> {
+    let id = Uuid::new_v4();
+
+    let presigned_url = state
+        .blob_store
+        .presigned_put_url(&format!("inputs/{id}.txt"), Duration::from_secs(60 * 5))
+        .await?
+        .ok_or_else(|| {
+            HTTPError(
+                StatusCode::NOT_IMPLEMENTED,
+                "Large inputs need a storage backend with presigned URLs, like S3".to_owned(),
+            )
+        })?;
+    Ok(Json(LargeInputResponse {
+        presigned_url,
+        input_id: id.to_string(),
+    }))
+}
+
+#[derive(Serialize)]
+struct ToolchainInfo {
+    id: String,
+    display_name: String,
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LanguageInfo {
+    id: String,
+    display_name: String,
+    version: Option<String>,
+    /// The first toolchain is the default.
+    toolchains: Vec<ToolchainInfo>,
+    default_compiler_options: String,
+    time_multiplier: f64,
+}
+
+/// Lists the supported languages so that clients don't have to hardcode them.
+async fn languages_handler() -> Result<Json<Vec<LanguageInfo>>, AppError> {
+    let languages = tokio::task::spawn_blocking(|| {
+        languages::registry()
+            .all()
+            .iter()
+            .map(|spec| LanguageInfo {
+                id: spec.id.0.clone(),
+                display_name: spec.display_name.clone(),
+                version: spec.version().map(str::to_owned),
+                toolchains: spec
+                    .toolchains
+                    .iter()
+                    .map(|toolchain| ToolchainInfo {
+                        id: toolchain.id.clone(),
+                        display_name: toolchain.display_name.clone(),
+                        version: toolchain.version().map(str::to_owned),
+                    })
+                    .collect(),
+                default_compiler_options: spec.default_compiler_options.clone(),
+                time_multiplier: spec.time_multiplier,
+            })
+            .collect()
+    })
+    .await?;
+    Ok(Json(languages))
+}
+
+/// The routes of the judge API.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(index_page))
+        .route("/compile", post(compile_handler))
+        .route("/execute", post(execute_handler))
+        .route("/compile-and-execute", post(compile_and_execute_handler))
+        .route(
+            "/compile-and-execute/stream",
+            post(compile_and_execute_stream_handler),
+        )
+        .route("/large-input", post(large_input_handler))
+        .route("/languages", get(languages_handler))
+        .route("/jobs", post(create_job_handler))
+        .route("/jobs/{id}", get(get_job_handler))
+        .with_state(state)
+}
@@ -1,80 +1,75 @@
-use std::fs;
-
-use aws_sdk_s3::presigning::PresigningConfig;
-use axum::{
-    extract::State,
-    routing::{get, post},
-    Json, Router,
-};
-use error::AppError;
+use axum::Router;
 use lambda_http::{run, tracing, Error};
+use online_judge_rust::{blob_store, executable, jobs, languages, pch, router, AppState};
+use tokio::signal::unix::{signal, SignalKind};
 
-mod compile;
-mod compile_and_execute;
-mod error;
-mod execute;
-mod run_command;
-mod types;
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing::init_default_subscriber();
 
-use compile::compile_handler;
-use compile_and_execute::compile_and_execute_handler;
-use execute::execute_handler;
-use serde::Serialize;
-use uuid::Uuid;
+    languages::init()?;
+    executable::check_signing_key()?;
 
-#[derive(Clone)]
-struct AppState {
-    s3_client: aws_sdk_s3::Client,
-}
+    // Probe compiler versions in the background so that they don't delay the first request.
+    std::thread::spawn(|| {
+        for spec in languages::registry().all() {
+            spec.version();
+            for toolchain in &spec.toolchains {
+                toolchain.version();
+            }
+        }
+    });
+    std::thread::spawn(pch::warm);
 
-async fn index_page() -> &'static str {
-    "Serverless Online Judge (Rust)"
+    let blob_store = blob_store::from_env().await?;
+    match listen_addr() {
+        Some(addr) => {
+            let jobs = Some(jobs::start_workers(blob_store.clone()));
+            serve(router(AppState { blob_store, jobs }), &addr).await
+        }
+        None => {
+            run(router(AppState {
+                blob_store,
+                jobs: None,
+            }))
+            .await
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct LargeInputResponse {
-    presigned_url: String,
-    input_id: String,
+/// The address to serve HTTP on when running outside Lambda, from `--listen <addr>` or the
+/// `LISTEN_ADDR` environment variable. None to run as a Lambda function.
+fn listen_addr() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--listen" {
+            return args.next();
+        }
+        if let Some(addr) = arg.strip_prefix("--listen=") {
+            return Some(addr.to_owned());
+        }
+    }
+    std::env::var("LISTEN_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
 }
 
-async fn large_input_handler(
-    State(state): State<AppState>,
-) -> Result<Json<LargeInputResponse>, AppError> {
-    let id = Uuid::new_v4();
-
-    let presigned_url = state
-        .s3_client
-        .put_object()
-        .bucket("online-judge-rust-data")
-        .key(format!("inputs/{id}.txt"))
-        .presigned(PresigningConfig::expires_in(
-            std::time::Duration::from_secs(60 * 5),
-        )?)
+/// Serves the app on a TCP port, for running on a plain server instead of Lambda. On SIGINT or
+/// SIGTERM, stops accepting connections and waits for in-flight requests to finish.
+async fn serve(app: Router, addr: &str) -> Result<(), Error> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
         .await?;
-    Ok(Json(LargeInputResponse {
-        presigned_url: presigned_url.uri().to_string(),
-        input_id: id.to_string(),
-    }))
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    tracing::init_default_subscriber();
-
-    let config = aws_config::load_from_env().await;
-    let s3_client = aws_sdk_s3::Client::new(&config);
-
-    fs::create_dir_all("/tmp/precompiled-headers/bits/stdc++.h.gch")?;
-
-    let state = AppState { s3_client };
-
-    let app = Router::new()
-        .route("/", get(index_page))
-        .route("/compile", post(compile_handler))
-        .route("/execute", post(execute_handler))
-        .route("/compile-and-execute", post(compile_and_execute_handler))
-        .route("/large-input", post(large_input_handler))
-        .with_state(state);
-
-    run(app).await
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("Shutting down");
 }
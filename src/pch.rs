@@ -0,0 +1,477 @@
+//! Precompiled headers for bits/stdc++.h.
+//!
+//! Building bits/stdc++.h can be very slow. We can substantially speed this up by precompiling
+//! headers: https://gcc.gnu.org/onlinedocs/gcc/Precompiled-Headers.html
+//!
+//! A precompiled header can only be used if flags like -std, -O2, -D_GLIBCXX_DEBUG and
+//! -fsanitize=address match the flags it was built with, while flags like -Wall don't matter. So
+//! we keep one precompiled header per toolchain and combination of relevant flags, built the first
+//! time it is needed. Programs compiled with flags we don't know about don't use a precompiled
+//! header. Precompiling is slow (~6s for C++23) and the headers are large, so they live in a
+//! size-bounded cache under /tmp where the least recently used ones are removed first.
+//!
+//! Several requests can reach the same container at once, so headers are built under a file lock,
+//! written atomically, and only used once they have been validated.
+//!
+//! We don't do this precompilation in the dockerfile because lambda disk read speeds are abysmally
+//! slow (~6 MB/s empirically), and the precompiled headers are quite large.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    os::unix::{fs::MetadataExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use nix::fcntl::{Flock, FlockArg};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    languages::{registry, PrecompiledHeaderStyle, ToolchainSpec},
+    run_command::{run_command, CommandOptions, CommandSpec},
+    types::SourceFile,
+};
+
+const PCH_DIR: &str = "/tmp/precompiled-headers";
+
+/// Default for `PCH_CACHE_MAX_MB`. A C++23 precompiled header is roughly 100 MB.
+const DEFAULT_MAX_CACHE_MB: u64 = 400;
+
+/// Limit on building or validating a precompiled header, so that a hung compiler doesn't block
+/// every request waiting for the variant's lock.
+const BUILD_TIMEOUT_MS: u32 = 60_000;
+
+/// Written to a variant's directory when building it fails. The variant isn't built again until
+/// [`FAILURE_TTL`] has passed, and compiles use no precompiled header in the meantime.
+const FAILED_MARKER: &str = "failed";
+
+const FAILURE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Prefixes of `-f` flags that change how the header is compiled, so that they get their own
+/// precompiled header.
+const CODEGEN_FLAGS: &[&str] = &[
+    "-fsanitize",
+    "-fno-sanitize",
+    "-fomit-frame-pointer",
+    "-fno-omit-frame-pointer",
+    "-fexceptions",
+    "-fno-exceptions",
+    "-frtti",
+    "-fno-rtti",
+    "-fstack-protector",
+    "-fno-stack-protector",
+    "-fwrapv",
+    "-ftrapv",
+    "-funroll-loops",
+];
+
+/// The subset of compiler options that must match between the precompiled header and the program.
+/// None if an option might change the header in a way we don't know about, in which case the
+/// program is compiled without a precompiled header rather than building one for that option.
+fn relevant_options(compiler_options: &[String]) -> Option<Vec<&str>> {
+    let mut options = Vec::new();
+    for option in compiler_options {
+        if let Some(definition) = option
+            .strip_prefix("-D")
+            .or_else(|| option.strip_prefix("-U"))
+        {
+            // Only macros that the standard library checks change the header. Other macros, like
+            // -DLOCAL, are usually only used by the program.
+            let name = definition.split('=').next().unwrap();
+            if name.starts_with("_GLIBCXX_") || name == "NDEBUG" {
+                options.push(option.as_str());
+            }
+        } else if option.starts_with("-std=")
+            || option.starts_with("-O")
+            || option.starts_with("-m")
+            || option.starts_with("-g")
+            || option == "-pthread"
+            || CODEGEN_FLAGS.iter().any(|flag| option.starts_with(flag))
+        {
+            options.push(option.as_str());
+        } else if !(option.starts_with("-W")
+            || option == "-w"
+            || option.starts_with("-pedantic")
+            || option.starts_with("-fdiagnostics")
+            || option.starts_with("-fmax-errors")
+            || option == "-static"
+            || option == "-lm")
+        {
+            return None;
+        }
+    }
+    Some(options)
+}
+
+/// Directory holding the precompiled header for one toolchain and set of relevant options.
+fn variant_dir(toolchain: &ToolchainSpec, options: &[&str]) -> PathBuf {
+    let mut hasher = Sha256::new();
+    for option in options {
+        hasher.update(option.as_bytes());
+        hasher.update([0]);
+    }
+    let hash = format!("{:x}", hasher.finalize());
+    Path::new(PCH_DIR).join(&toolchain.id).join(&hash[..16])
+}
+
+/// A precompiled header that is ready to use. It can't be evicted until this is dropped.
+#[derive(Default)]
+pub struct PrecompiledHeader {
+    /// Compiler flags needed to use the precompiled header.
+    pub flags: Vec<String>,
+    _lock: Option<Flock<File>>,
+}
+
+/// Returns a precompiled bits/stdc++.h matching `compiler_options`, building it if needed. The
+/// result has no flags if the program doesn't include bits/stdc++.h or the toolchain doesn't
+/// support precompiled headers.
+pub fn precompiled_header(
+    toolchain: Option<&ToolchainSpec>,
+    compiler_options: &[String],
+    files: &[SourceFile],
+) -> Result<PrecompiledHeader> {
+    let Some(toolchain) = toolchain.filter(|toolchain| toolchain.precompiled_header.is_some())
+    else {
+        return Ok(PrecompiledHeader::default());
+    };
+
+    if !files
+        .iter()
+        .any(|file| file.content.contains("#include <bits/stdc++.h>"))
+    {
+        return Ok(PrecompiledHeader::default());
+    }
+
+    build(toolchain, compiler_options)
+}
+
+/// Locks a variant directory, creating it if needed.
+///
+/// Readers take a shared lock for as long as they use the precompiled header, and builders and
+/// eviction take an exclusive lock, so that two requests never build the same header at once and
+/// a header is never removed while it is in use.
+fn lock_variant(dir: &Path, arg: FlockArg) -> Result<Flock<File>> {
+    let lock_path = dir.join("lock");
+    loop {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        let lock = Flock::lock(file, arg)
+            .map_err(|(_, errno)| anyhow!("Failed to lock {}: {errno}", lock_path.display()))?;
+        // If the variant was evicted while we were waiting, our lock file is no longer the one in
+        // the directory, so try again.
+        match fs::metadata(&lock_path) {
+            Ok(metadata) if metadata.ino() == lock.metadata()?.ino() => return Ok(lock),
+            _ => continue,
+        }
+    }
+}
+
+/// Whether the precompiled header finished building and passed validation. A header without a
+/// matching stamp was left behind by a build that crashed or timed out.
+fn is_valid(path: &Path, stamp_path: &Path) -> bool {
+    let Ok(metadata) = fs::metadata(path) else {
+        return false;
+    };
+    fs::read_to_string(stamp_path)
+        .ok()
+        .and_then(|stamp| stamp.trim().parse::<u64>().ok())
+        == Some(metadata.len())
+}
+
+/// Returns the precompiled header for `compiler_options`, building it if it doesn't exist yet.
+fn build(toolchain: &ToolchainSpec, compiler_options: &[String]) -> Result<PrecompiledHeader> {
+    let precompiled_header = toolchain
+        .precompiled_header
+        .as_ref()
+        .ok_or_else(|| anyhow!("{} doesn't support precompiled headers", toolchain.id))?;
+    let Some(options) = relevant_options(compiler_options) else {
+        return Ok(PrecompiledHeader::default());
+    };
+    let dir = variant_dir(toolchain, &options);
+    let stamp_path = dir.join("valid");
+
+    let (precompiled_header_path, flags) = match precompiled_header.style {
+        PrecompiledHeaderStyle::Gcc => (
+            dir.join("bits/stdc++.h.gch"),
+            vec![format!("-I{}", dir.to_str().unwrap())],
+        ),
+        PrecompiledHeaderStyle::Clang => {
+            let path = dir.join("stdc++.pch");
+            let flags = vec!["-include-pch".to_owned(), path.to_str().unwrap().to_owned()];
+            (path, flags)
+        }
+    };
+
+    let lock = lock_variant(&dir, FlockArg::LockShared)?;
+    if is_valid(&precompiled_header_path, &stamp_path) {
+        // Mark the variant as recently used.
+        let _ = File::open(&dir).and_then(|dir| dir.set_modified(SystemTime::now()));
+        return Ok(PrecompiledHeader {
+            flags,
+            _lock: Some(lock),
+        });
+    }
+    if failed_recently(&dir) {
+        return Ok(PrecompiledHeader::default());
+    }
+    drop(lock);
+
+    let lock = lock_variant(&dir, FlockArg::LockExclusive)?;
+    // Another request may have built it, or failed to, while we were waiting for the lock.
+    if failed_recently(&dir) {
+        return Ok(PrecompiledHeader::default());
+    }
+    if !is_valid(&precompiled_header_path, &stamp_path) {
+        let _ = fs::remove_file(&stamp_path);
+        let _ = fs::remove_file(&precompiled_header_path);
+        evict(&dir)?;
+        fs::create_dir_all(precompiled_header_path.parent().unwrap())?;
+
+        let result = build_variant(
+            toolchain,
+            &precompiled_header.header,
+            &precompiled_header_path,
+            &options,
+            &flags,
+        );
+        if let Err(err) = result {
+            let _ = fs::remove_file(&precompiled_header_path);
+            // Later compiles skip this variant for a while rather than each waiting for the same
+            // failing build.
+            let _ = fs::write(dir.join(FAILED_MARKER), format!("{err:#}"));
+            return Err(err);
+        }
+        let _ = fs::remove_file(dir.join(FAILED_MARKER));
+        let tmp_stamp_path = stamp_path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+        fs::write(
+            &tmp_stamp_path,
+            fs::metadata(&precompiled_header_path)?.len().to_string(),
+        )?;
+        fs::rename(tmp_stamp_path, &stamp_path)?;
+    }
+
+    lock.relock(FlockArg::LockShared)
+        .context("Failed to downgrade precompiled header lock")?;
+    Ok(PrecompiledHeader {
+        flags,
+        _lock: Some(lock),
+    })
+}
+
+/// Whether building the variant in `dir` failed within the last [`FAILURE_TTL`].
+fn failed_recently(dir: &Path) -> bool {
+    fs::metadata(dir.join(FAILED_MARKER))
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            modified
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed < FAILURE_TTL)
+        })
+}
+
+/// Builds `header` to `path` and validates it. Must be called with the variant's exclusive lock.
+fn build_variant(
+    toolchain: &ToolchainSpec,
+    header: &str,
+    path: &Path,
+    options: &[&str],
+    flags: &[String],
+) -> Result<()> {
+    // Build to a temporary file and rename it into place, so that a build that is killed partway
+    // through never leaves a truncated header where the compiler would find it.
+    let tmp_path = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+    // todo: disable in local development
+    let mut argv = vec![
+        toolchain.compiler.clone(),
+        "-x".to_owned(),
+        "c++-header".to_owned(),
+    ];
+    argv.extend(["-o".to_owned(), tmp_path.to_str().unwrap().to_owned()]);
+    argv.extend(options.iter().map(|option| option.to_string()));
+    argv.push(header.to_owned());
+    let output = run(&argv, Bytes::new()).context("Failed to precompile header");
+    if let Err(err) = output {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    fs::rename(&tmp_path, path)?;
+
+    // Check that the compiler accepts the freshly built header.
+    let mut argv = [
+        &toolchain.compiler,
+        "-x",
+        "c++",
+        "-fsyntax-only",
+        "-Winvalid-pch",
+        "-Werror=invalid-pch",
+    ]
+    .map(str::to_owned)
+    .to_vec();
+    argv.extend(flags.iter().cloned());
+    argv.extend(options.iter().map(|option| option.to_string()));
+    argv.push("-".to_owned());
+    run(&argv, Bytes::from_static(b"#include <bits/stdc++.h>\n"))
+        .context("Precompiled header failed validation")
+}
+
+/// Runs a compiler command with [`BUILD_TIMEOUT_MS`], failing unless it succeeds.
+fn run(argv: &[String], stdin: Bytes) -> Result<()> {
+    let command = CommandSpec::from_argv(argv, PathBuf::from(PCH_DIR)).unwrap();
+    let output = run_command(
+        &command,
+        CommandOptions {
+            stdin,
+            timeout_ms: BUILD_TIMEOUT_MS,
+            memory_limit_mb: None,
+        },
+    )?;
+    if output.timed_out {
+        return Err(anyhow!("Timed out after {BUILD_TIMEOUT_MS} ms"));
+    }
+    if !ExitStatus::from_raw(output.exit_code).success() {
+        return Err(anyhow!("{}", output.stderr));
+    }
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Removes the least recently used precompiled headers while the cache is over its size limit,
+/// keeping `keep`.
+fn evict(keep: &Path) -> Result<()> {
+    let max_bytes = env::var("PCH_CACHE_MAX_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CACHE_MB)
+        * 1_000_000;
+
+    let Ok(toolchain_dirs) = fs::read_dir(PCH_DIR) else {
+        return Ok(());
+    };
+    let mut variants = toolchain_dirs
+        .filter_map(|entry| fs::read_dir(entry.ok()?.path()).ok())
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+            Some((dir_size(&path), modified, path))
+        })
+        .filter(|(_, _, path)| path != keep)
+        .collect::<Vec<_>>();
+
+    let mut total: u64 = variants.iter().map(|(size, _, _)| size).sum();
+    variants.sort_by_key(|(_, modified, _)| *modified);
+    for (size, _, path) in variants {
+        if total <= max_bytes {
+            break;
+        }
+        // Skip headers that are in use or being built.
+        let Ok(file) = File::open(path.join("lock")) else {
+            continue;
+        };
+        let Ok(_lock) = Flock::lock(file, FlockArg::LockExclusiveNonblock) else {
+            continue;
+        };
+        println!("Evicting precompiled header {}", path.display());
+        fs::remove_dir_all(path)?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Builds the precompiled headers listed in each toolchain's `warm_pch_options`, so that the most
+/// common flag combinations are ready before they are needed.
+///
+/// Meant to be run in a background thread after a cold start. Note that lambda freezes the
+/// container between invocations, so this only makes progress while requests are being handled.
+pub fn warm() {
+    for spec in registry().all() {
+        for toolchain in &spec.toolchains {
+            for compiler_options in &toolchain.warm_pch_options {
+                let result = shell_words::split(compiler_options)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|options| build(toolchain, &options));
+                if let Err(err) = result {
+                    println!(
+                        "Warning: Failed to warm precompiled header for {} {compiler_options}: {err}",
+                        toolchain.id
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(options: &str) -> Vec<String> {
+        shell_words::split(options).unwrap()
+    }
+
+    #[test]
+    fn keeps_flags_that_change_the_header() {
+        let options = options("-std=c++23 -O2 -Wall -DLOCAL -D_GLIBCXX_DEBUG -UNDEBUG -pthread");
+        assert_eq!(
+            relevant_options(&options),
+            Some(vec![
+                "-std=c++23",
+                "-O2",
+                "-D_GLIBCXX_DEBUG",
+                "-UNDEBUG",
+                "-pthread"
+            ])
+        );
+    }
+
+    #[test]
+    fn ignores_unrelated_macros() {
+        assert_eq!(
+            relevant_options(&options("-O2 -DLOCAL -DN=100")),
+            relevant_options(&options("-O2")),
+        );
+    }
+
+    #[test]
+    fn keeps_codegen_flags() {
+        assert_eq!(
+            relevant_options(&options("-O2 -fsanitize=address,undefined -fno-exceptions")),
+            Some(vec![
+                "-O2",
+                "-fsanitize=address,undefined",
+                "-fno-exceptions"
+            ])
+        );
+    }
+
+    #[test]
+    fn skips_unknown_flags() {
+        assert_eq!(
+            relevant_options(&options("-O2 -fconcepts-diagnostics-depth=2")),
+            None
+        );
+        assert_eq!(relevant_options(&options("-fno-builtin")), None);
+    }
+}
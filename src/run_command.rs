@@ -1,132 +1,331 @@
-//! Provides a function to run a command and return the output.
+//! Provides a function to run a command and return the output, optionally streaming the output
+//! while the command runs.
+//!
+//! Commands are run directly from an argument list rather than through a shell, so paths and
+//! options never need quoting. Resource limits, the timeout, and time and memory measurement are
+//! applied here instead of by `ulimit`, `timeout` and `/usr/bin/time`.
 
-use std::io::Write;
-use std::path::Path;
-use std::process::Stdio;
-use std::{os::unix::process::ExitStatusExt, process::Command, str};
+use std::{
+    io::{Read, Write},
+    ops::ControlFlow,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use anyhow::{anyhow, Context};
+use anyhow::{Context, Result};
 use bytes::Bytes;
-use nix::sys::signal::Signal;
-use regex::Regex;
+use nix::{
+    sys::{
+        resource::{getrlimit, setrlimit, Resource},
+        signal::{killpg, Signal},
+    },
+    unistd::Pid,
+};
 use serde::{Deserialize, Serialize};
 
+/// A command to run, given as a program and its arguments rather than a shell command line.
+pub struct CommandSpec {
+    /// Looked up in `PATH` unless it contains a slash, in which case it is relative to `cwd`.
+    pub program: String,
+    pub args: Vec<String>,
+    /// Extra environment variables for the command.
+    pub env: Vec<(String, String)>,
+    pub cwd: PathBuf,
+}
+
+impl CommandSpec {
+    /// Builds a command from an argument list whose first element is the program.
+    pub fn from_argv(argv: &[String], cwd: PathBuf) -> Option<CommandSpec> {
+        let (program, args) = argv.split_first()?;
+        Some(CommandSpec {
+            program: program.clone(),
+            args: args.to_vec(),
+            env: Vec::new(),
+            cwd,
+        })
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CommandOptions {
     pub stdin: Bytes,
     pub timeout_ms: u32,
+    /// Address space limit for the command. None for no limit.
+    pub memory_limit_mb: Option<u32>,
 }
 
-#[derive(Serialize)]
+/// Commands that time out are reported with the wait status for exit status 124, which is what
+/// the `timeout` command used to exit with, so that clients can keep checking for it. A command
+/// can also exit with 124 itself, so check [`CommandOutput::timed_out`] instead.
+pub const TIMEOUT_WAIT_STATUS: i32 = 124 << 8;
+
+#[derive(Serialize, Deserialize)]
 pub struct CommandOutput {
     pub stdout: String,
     pub stderr: String,
-    pub wall_time: String, // time format is 0:00.00
-    pub memory_usage: String,
+    pub wall_time: String,    // time format is 0.00, in seconds
+    pub memory_usage: String, // maximum resident set size in kilobytes
 
     /// The underlying raw wait status. Note that this is different from an exit status.
     pub exit_code: i32,
     pub exit_signal: Option<String>,
+    /// Whether the command was killed for running past its timeout.
+    #[serde(default)]
+    pub timed_out: bool,
 }
 
-struct TimingOutput {
-    stderr: String,
-    wall_time: String,
-    memory_usage: String,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
 }
 
-fn parse_timing_stderr(stderr: &str) -> Result<TimingOutput> {
-    let start_index = stderr.rfind("\tCommand being timed:");
-    if start_index.is_none() {
-        return Err(anyhow!(
-            "Failed to parse timing output: Couldn't find \"Command being timed\""
-        ));
-    }
-    let start_index = start_index.unwrap();
-    let rest_of_string = stderr[..start_index].to_string();
-    let time_output = &stderr[start_index..];
-
-    let wall_time_re =
-        Regex::new(r"\tElapsed \(wall clock\) time \(h:mm:ss or m:ss\): (.+)").unwrap();
-    let memory_usage_re = Regex::new(r"\tMaximum resident set size \(kbytes\): (.+)").unwrap();
-
-    let wall_time = wall_time_re
-        .captures(time_output)
-        .and_then(|caps| caps.get(1))
-        .map(|m| m.as_str().to_string())
-        .ok_or(anyhow!("Failed to parse wall time: Couldn't match regex"))?;
-
-    let memory_usage = memory_usage_re
-        .captures(time_output)
-        .and_then(|caps| caps.get(1))
-        .map(|m| m.as_str().to_string())
-        .ok_or(anyhow!(
-            "Failed to parse memory usage: Couldn't match regex"
-        ))?;
-
-    let wall_time = if wall_time.len() > 3 {
-        wall_time[3..].to_string()
-    } else {
-        return Err(anyhow!(
-            "Failed to parse wall time: matched regex length less than expected"
-        ));
-    };
+/// Called with each chunk of output as the command produces it. Chunks may end in the middle of
+/// a UTF-8 character. Returning `Break` kills the command, such as when nobody is listening to
+/// the output anymore.
+pub type OutputCallback = Arc<dyn Fn(OutputStream, &[u8]) -> ControlFlow<()> + Send + Sync>;
 
-    Ok(TimingOutput {
-        stderr: rest_of_string,
-        wall_time,
-        memory_usage,
+/// Messages to the thread that enforces the timeout.
+enum TimerMessage {
+    /// The command has exited.
+    Finished,
+    /// `on_output` asked for the command to be killed.
+    Kill,
+}
+
+/// Reads a pipe until it is closed, passing each chunk to `on_output` as it arrives.
+fn read_to_end(
+    mut pipe: impl Read + Send + 'static,
+    stream: OutputStream,
+    on_output: Option<OutputCallback>,
+    timer: mpsc::Sender<TimerMessage>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut on_output = on_output;
+        let mut output = Vec::new();
+        let mut buffer = [0; 8192];
+        loop {
+            match pipe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => {
+                    if let Some(callback) = &on_output {
+                        if callback(stream, &buffer[..len]).is_break() {
+                            // Keep reading until the pipe closes, so that the command never
+                            // blocks on a full pipe before it is killed.
+                            let _ = timer.send(TimerMessage::Kill);
+                            on_output = None;
+                        }
+                    }
+                    output.extend_from_slice(&buffer[..len]);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        output
     })
 }
 
-pub fn run_command(
-    command: &str,
-    working_dir: &Path,
+/// Waits for `pid` to exit without reaping it, so that its pid and process group can't be reused
+/// while they might still be signaled.
+fn wait_for_exit(pid: Pid) -> Result<()> {
+    // SAFETY: siginfo_t is plain data that waitid fills in.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: info is valid for writes.
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid.as_raw() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("Failed to wait for command");
+        }
+    }
+}
+
+/// Waits for `pid` to exit and reaps it, returning its wait status and resource usage.
+fn wait4(pid: Pid) -> Result<(i32, libc::rusage)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain data that wait4 fills in.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: status and rusage are valid for writes.
+        let result = unsafe { libc::wait4(pid.as_raw(), &mut status, 0, &mut rusage) };
+        if result == pid.as_raw() {
+            return Ok((status, rusage));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("Failed to wait for command");
+        }
+    }
+}
+
+pub fn run_command(command: &CommandSpec, options: CommandOptions) -> Result<CommandOutput> {
+    run_command_streaming(command, options, None)
+}
+
+/// Like [`run_command`], but also passes output to `on_output` as the command produces it.
+pub fn run_command_streaming(
+    command: &CommandSpec,
     options: CommandOptions,
+    on_output: Option<OutputCallback>,
 ) -> Result<CommandOutput> {
-    let mut process = Command::new("sh")
-        .arg("-c")
-        .arg(format!(
-            "{} {}s {command}",
-            if cfg!(target_os = "macos") {
-                // ulimit -s unlimited does not work on mac os
-                // use `brew install gtime` to install linux time on mac os
-                // use `brew install timeout` to install linux timeout on mac os
-                "gtime -v timeout"
-            } else {
-                "ulimit -c 0 && ulimit -s unlimited && /usr/bin/time -v /usr/bin/timeout"
-            },
-            options.timeout_ms / 1000
-        ))
-        .current_dir(working_dir)
+    let memory_limit_bytes = options
+        .memory_limit_mb
+        .map(|memory_limit_mb| memory_limit_mb as u64 * 1024 * 1024);
+    let mut process = Command::new(&command.program);
+    process
+        .args(&command.args)
+        .envs(command.env.iter().cloned())
+        .current_dir(&command.cwd)
+        // Run the command in its own process group so that the timeout also kills anything it
+        // started.
+        .process_group(0)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: Only async-signal-safe system calls are made between fork and exec.
+    unsafe {
+        process.pre_exec(move || {
+            setrlimit(Resource::RLIMIT_CORE, 0, 0)?;
+            // Competitive programs often recurse deeply, so raise the stack size as far as allowed.
+            let (_, max_stack) = getrlimit(Resource::RLIMIT_STACK)?;
+            setrlimit(Resource::RLIMIT_STACK, max_stack, max_stack)?;
+            if let Some(memory_limit_bytes) = memory_limit_bytes {
+                setrlimit(Resource::RLIMIT_AS, memory_limit_bytes, memory_limit_bytes)?;
+            }
+            Ok(())
+        });
+    }
+
+    let start = Instant::now();
+    let mut process = process
         .spawn()
-        .with_context(|| format!("Failed to spawn command {command}"))?;
+        .with_context(|| format!("Failed to spawn command {}", command.program))?;
+    let pid = Pid::from_raw(process.id() as i32);
 
     let mut stdin_pipe = process.stdin.take().expect("Failed to open stdin");
-    std::thread::spawn(move || {
+    let stdin = options.stdin;
+    thread::spawn(move || {
         // Note: This may be due to a broken pipe if the program closes their stdin pipe.
         // This thread panicing does not crash the main thread.
-        let _ = stdin_pipe.write_all(&options.stdin);
+        let _ = stdin_pipe.write_all(&stdin);
+    });
+    let (timer_sender, timer_receiver) = mpsc::channel();
+    let stdout = read_to_end(
+        process.stdout.take().expect("Failed to open stdout"),
+        OutputStream::Stdout,
+        on_output.clone(),
+        timer_sender.clone(),
+    );
+    let stderr = read_to_end(
+        process.stderr.take().expect("Failed to open stderr"),
+        OutputStream::Stderr,
+        on_output,
+        timer_sender.clone(),
+    );
+
+    // Only this thread kills the command before it exits, and it stops once the command has
+    // exited, so the command is never signaled after it has been reaped.
+    let deadline = start + Duration::from_millis(options.timeout_ms as u64);
+    let timer = thread::spawn(move || loop {
+        match timer_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(TimerMessage::Kill) => {
+                let _ = killpg(pid, Signal::SIGKILL);
+            }
+            Ok(TimerMessage::Finished) | Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let _ = killpg(pid, Signal::SIGKILL);
+                return true;
+            }
+        }
     });
 
-    let process = process.wait_with_output()?;
+    wait_for_exit(pid)?;
+    let wall_time = start.elapsed();
+    let _ = timer_sender.send(TimerMessage::Finished);
+    let timed_out = timer.join().expect("Timeout thread panicked");
+    // Kill any background processes the command left behind, which would otherwise keep its
+    // output pipes open.
+    let _ = killpg(pid, Signal::SIGKILL);
+    let (wait_status, rusage) = wait4(pid)?;
 
-    let timing_output = parse_timing_stderr(String::from_utf8_lossy(&process.stderr).as_ref())?;
+    let stdout = stdout.join().expect("Failed to read stdout");
+    let stderr = stderr.join().expect("Failed to read stderr");
+
+    let (exit_code, exit_signal) = if timed_out {
+        (TIMEOUT_WAIT_STATUS, None)
+    } else {
+        let signal = ExitStatus::from_raw(wait_status).signal();
+        (
+            wait_status,
+            signal.map(|signal| {
+                Signal::try_from(signal).map_or(format!("Unknown signal {signal}"), |signal| {
+                    signal.to_string()
+                })
+            }),
+        )
+    };
+    // ru_maxrss is in kilobytes on Linux but bytes on macOS.
+    let memory_usage_kb = if cfg!(target_os = "macos") {
+        rusage.ru_maxrss / 1024
+    } else {
+        rusage.ru_maxrss
+    };
 
     Ok(CommandOutput {
-        exit_code: process.status.into_raw(),
-        exit_signal: process.status.signal().map(|signal| {
-            Signal::try_from(signal).map_or(format!("Unknown signal {signal}"), |signal| {
-                signal.to_string()
-            })
-        }),
-        stdout: String::from_utf8_lossy(&process.stdout).into_owned(),
-        stderr: timing_output.stderr,
-        wall_time: timing_output.wall_time,
-        memory_usage: timing_output.memory_usage,
+        exit_code,
+        exit_signal,
+        timed_out,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        wall_time: format!("{:.2}", wall_time.as_secs_f64()),
+        memory_usage: memory_usage_kb.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_sh(script: &str, timeout_ms: u32) -> CommandOutput {
+        let argv = ["sh", "-c", script].map(str::to_owned);
+        let command = CommandSpec::from_argv(&argv, std::env::temp_dir()).unwrap();
+        run_command(
+            &command,
+            CommandOptions {
+                stdin: Bytes::new(),
+                timeout_ms,
+                memory_limit_mb: None,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn exiting_with_124_is_not_a_timeout() {
+        let output = run_sh("exit 124", 10_000);
+        assert_eq!(output.exit_code, 124 << 8);
+        assert!(!output.timed_out);
+    }
+
+    #[test]
+    fn reports_timeouts() {
+        let output = run_sh("sleep 10", 100);
+        assert_eq!(output.exit_code, TIMEOUT_WAIT_STATUS);
+        assert!(output.timed_out);
+    }
+}
@@ -0,0 +1,346 @@
+//! Explains why a program crashed, in terms beginners can understand.
+//!
+//! A crash is reported as a raw wait status and maybe a signal name like "SIGSEGV", which mean
+//! little to most users. This classifies the crash from the exit status, signal and stderr.
+
+use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+use nix::sys::signal::Signal;
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeErrorKind {
+    SegmentationFault,
+    DivisionByZero,
+    Abort,
+    /// Killed with SIGKILL, usually for running out of memory.
+    Killed,
+    JavaException,
+    PythonException,
+    /// Killed by some other signal.
+    Signal,
+    /// Exited normally with a nonzero exit status.
+    NonZeroExitStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RuntimeErrorReason {
+    pub kind: RuntimeErrorKind,
+    /// Human-readable explanation of what probably went wrong.
+    pub explanation: String,
+    /// The exception class or type, like "java.lang.ArrayIndexOutOfBoundsException" or
+    /// "IndexError".
+    pub exception_type: Option<String>,
+}
+
+/// The exit status of a process that exited normally. None if it was killed by a signal.
+pub fn exit_status(wait_status: i32) -> Option<i32> {
+    ExitStatus::from_raw(wait_status).code()
+}
+
+/// The signal that killed the program, if any.
+///
+/// Programs are run directly, so a signal shows up in the wait status. Legacy executables are run
+/// with `sh -c`, though, and when a shell's child is killed by a signal, the shell exits with
+/// status 128 + the signal number instead of being killed itself.
+fn terminating_signal(wait_status: i32, runs_in_shell: bool) -> Option<Signal> {
+    let status = ExitStatus::from_raw(wait_status);
+    let signal = status.signal().or_else(|| {
+        status
+            .code()
+            .filter(|code| runs_in_shell && *code > 128)
+            .map(|code| code - 128)
+    })?;
+    Signal::try_from(signal).ok()
+}
+
+/// An uncaught Java or Python exception.
+#[derive(Serialize, Debug)]
+pub struct Exception {
+    /// Like "java.lang.ArrayIndexOutOfBoundsException" or "IndexError".
+    #[serde(rename = "type")]
+    pub exception_type: String,
+    pub message: Option<String>,
+    /// The user's source file where the exception was thrown, like "Main.java".
+    pub file: Option<String>,
+    /// Line in `file`. None if no frame of the stack trace is in the user's code.
+    pub line: Option<u32>,
+}
+
+/// Whether a file name from a stack trace refers to one of the user's source files. Stack traces
+/// show absolute paths (Python) or only the file name (Java), so compare by suffix.
+fn find_source_file<'a>(path: &str, source_files: &'a [String]) -> Option<&'a String> {
+    source_files.iter().find(|source_file| {
+        let file_name = source_file.rsplit('/').next().unwrap_or(source_file);
+        path == source_file.as_str()
+            || path.ends_with(&format!("/{source_file}"))
+            || path == file_name
+    })
+}
+
+/// Parses a Java stack trace like
+///
+/// ```text
+/// Exception in thread "main" java.lang.ArrayIndexOutOfBoundsException: Index 5 out of bounds for length 3
+///         at Main.solve(Main.java:12)
+///         at Main.main(Main.java:5)
+/// ```
+pub fn java_exception(stderr: &str, source_files: &[String]) -> Option<Exception> {
+    let re =
+        Regex::new(r#"Exception in thread "[^"]*" (?<type>[\w.$]+)(: (?<message>.*))?"#).unwrap();
+    let frame_re = Regex::new(r"^\s+at .+\((?<file>[^:()]+):(?<line>\d+)\)$").unwrap();
+
+    let captures = re.captures(stderr)?;
+    let trace = &stderr[captures.get(0).unwrap().end()..];
+    // Frames are listed innermost first.
+    let frame = trace
+        .lines()
+        .filter_map(|line| frame_re.captures(line))
+        .find_map(|frame| {
+            find_source_file(&frame["file"], source_files)
+                .map(|file| (file.clone(), frame["line"].parse().ok()))
+        });
+
+    Some(Exception {
+        exception_type: captures["type"].to_owned(),
+        message: captures.name("message").map(|m| m.as_str().to_owned()),
+        file: frame.as_ref().map(|(file, _)| file.clone()),
+        line: frame.and_then(|(_, line)| line),
+    })
+}
+
+/// Parses a Python traceback like
+///
+/// ```text
+/// Traceback (most recent call last):
+///   File "/tmp/.tmpAbC123/program.py", line 3, in <module>
+///     f()
+/// IndexError: list index out of range
+/// ```
+pub fn python_exception(stderr: &str, source_files: &[String]) -> Option<Exception> {
+    let start = stderr.rfind("Traceback (most recent call last):")?;
+    let traceback = &stderr[start..];
+    let re = Regex::new(r"^(?<type>[A-Za-z_][\w.]*)(: (?<message>.*))?$").unwrap();
+    let frame_re = Regex::new(r#"^\s*File "(?<file>[^"]+)", line (?<line>\d+)"#).unwrap();
+
+    let captures = traceback.lines().rev().find_map(|line| re.captures(line))?;
+    // Frames are listed outermost first.
+    let frame = traceback
+        .lines()
+        .rev()
+        .filter_map(|line| frame_re.captures(line))
+        .find_map(|frame| {
+            find_source_file(&frame["file"], source_files)
+                .map(|file| (file.clone(), frame["line"].parse().ok()))
+        });
+
+    Some(Exception {
+        exception_type: captures["type"].to_owned(),
+        message: captures.name("message").map(|m| m.as_str().to_owned()),
+        file: frame.as_ref().map(|(file, _)| file.clone()),
+        line: frame.and_then(|(_, line)| line),
+    })
+}
+
+/// Finds an uncaught Java or Python exception in `stderr`.
+pub fn parse_exception(stderr: &str, source_files: &[String]) -> Option<Exception> {
+    java_exception(stderr, source_files).or_else(|| python_exception(stderr, source_files))
+}
+
+/// Classifies a crash. Returns None if the program exited successfully. `runs_in_shell` is set
+/// for legacy executables; see [`crate::executable::Manifest::runs_in_shell`].
+pub fn classify(wait_status: i32, stderr: &str, runs_in_shell: bool) -> Option<RuntimeErrorReason> {
+    if ExitStatus::from_raw(wait_status).success() {
+        return None;
+    }
+
+    let reason = |kind, explanation: &str| RuntimeErrorReason {
+        kind,
+        explanation: explanation.to_owned(),
+        exception_type: None,
+    };
+
+    if let Some(Exception { exception_type, .. }) = java_exception(stderr, &[]) {
+        let explanation = match exception_type.rsplit('.').next().unwrap_or_default() {
+            "ArrayIndexOutOfBoundsException"
+            | "StringIndexOutOfBoundsException"
+            | "IndexOutOfBoundsException" => {
+                "An array, string or list was accessed at an index that is out of bounds."
+            }
+            "ArithmeticException" => "An arithmetic error occurred, usually division by zero.",
+            "NullPointerException" => "A null reference was used as if it were an object.",
+            "StackOverflowError" => "The recursion went too deep and ran out of stack space.",
+            "OutOfMemoryError" => "The program ran out of memory.",
+            "NumberFormatException" => {
+                "A string couldn't be parsed as a number. Check the input format."
+            }
+            "InputMismatchException" | "NoSuchElementException" => {
+                "The input didn't match what the program tried to read, or ran out."
+            }
+            _ => "The program threw an exception that wasn't caught.",
+        };
+        return Some(RuntimeErrorReason {
+            exception_type: Some(exception_type),
+            ..reason(RuntimeErrorKind::JavaException, explanation)
+        });
+    }
+
+    if let Some(Exception { exception_type, .. }) = python_exception(stderr, &[]) {
+        let explanation = match exception_type.as_str() {
+            "IndexError" => "A list or string was accessed at an index that is out of range.",
+            "KeyError" => "A dictionary was accessed with a key that isn't in it.",
+            "ZeroDivisionError" => "The program divided by zero.",
+            "RecursionError" => {
+                "The recursion went too deep. Python's default limit can be raised with \
+                 sys.setrecursionlimit."
+            }
+            "ValueError" => "A value had the right type but an invalid value, like int(\"abc\").",
+            "TypeError" => "An operation was applied to a value of the wrong type.",
+            "NameError" => "A variable or function was used before it was defined.",
+            "EOFError" => "The program tried to read more input than was given.",
+            "MemoryError" => "The program ran out of memory.",
+            _ => "The program raised an exception that wasn't caught.",
+        };
+        return Some(RuntimeErrorReason {
+            exception_type: Some(exception_type),
+            ..reason(RuntimeErrorKind::PythonException, explanation)
+        });
+    }
+
+    if let Some(signal) = terminating_signal(wait_status, runs_in_shell) {
+        return Some(match signal {
+            Signal::SIGSEGV | Signal::SIGBUS => reason(
+                RuntimeErrorKind::SegmentationFault,
+                "Segmentation fault: the program accessed memory it shouldn't have. This is usually \
+                 an out-of-bounds array index, a null or dangling pointer, or a stack overflow from \
+                 very deep recursion.",
+            ),
+            Signal::SIGFPE => reason(
+                RuntimeErrorKind::DivisionByZero,
+                "Floating point exception: usually an integer division or modulo by zero.",
+            ),
+            Signal::SIGABRT => reason(
+                RuntimeErrorKind::Abort,
+                "The program aborted, usually because an assert failed or an exception wasn't \
+                 caught (such as std::out_of_range from vector::at, or std::bad_alloc when out of \
+                 memory).",
+            ),
+            Signal::SIGKILL => reason(
+                RuntimeErrorKind::Killed,
+                "The program was killed, usually because it used too much memory.",
+            ),
+            signal => reason(
+                RuntimeErrorKind::Signal,
+                &format!("The program was killed by signal {signal}."),
+            ),
+        });
+    }
+
+    let exit_status = exit_status(wait_status).unwrap_or_default();
+    Some(reason(
+        RuntimeErrorKind::NonZeroExitStatus,
+        &format!(
+            "The program exited with status {exit_status}. Make sure main returns 0 and that \
+             exit() isn't called with a nonzero status."
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The wait status of a process that exited with `code`.
+    fn exited(code: i32) -> i32 {
+        code << 8
+    }
+
+    #[test]
+    fn classifies_signals() {
+        let reason = classify(Signal::SIGSEGV as i32, "", false).unwrap();
+        assert_eq!(reason.kind, RuntimeErrorKind::SegmentationFault);
+    }
+
+    #[test]
+    fn only_treats_high_exit_statuses_as_signals_for_shells() {
+        let reason = classify(exited(128 + Signal::SIGSEGV as i32), "", false).unwrap();
+        assert_eq!(reason.kind, RuntimeErrorKind::NonZeroExitStatus);
+        let reason = classify(exited(128 + Signal::SIGSEGV as i32), "", true).unwrap();
+        assert_eq!(reason.kind, RuntimeErrorKind::SegmentationFault);
+    }
+
+    #[test]
+    fn ignores_successful_runs() {
+        assert!(classify(0, "", false).is_none());
+    }
+
+    fn sources(files: &[&str]) -> Vec<String> {
+        files.iter().map(|&file| file.to_owned()).collect()
+    }
+
+    #[test]
+    fn skips_jdk_frames_in_java_traces() {
+        let stderr = "Exception in thread \"main\" java.lang.IndexOutOfBoundsException: \
+                      Index 5 out of bounds for length 3
+\tat java.base/java.util.Objects.checkIndex(Objects.java:385)
+\tat java.base/java.util.ArrayList.get(ArrayList.java:427)
+\tat Main.solve(Main.java:12)
+\tat Main.main(Main.java:5)
+";
+        let exception = java_exception(stderr, &sources(&["Main.java"])).unwrap();
+        assert_eq!(
+            exception.exception_type,
+            "java.lang.IndexOutOfBoundsException"
+        );
+        assert_eq!(
+            exception.message.as_deref(),
+            Some("Index 5 out of bounds for length 3")
+        );
+        assert_eq!(exception.file.as_deref(), Some("Main.java"));
+        assert_eq!(exception.line, Some(12));
+    }
+
+    #[test]
+    fn matches_absolute_python_paths() {
+        let stderr = "Traceback (most recent call last):
+  File \"/tmp/.tmpX/program.py\", line 3, in <module>
+    f()
+  File \"/tmp/.tmpX/program.py\", line 2, in f
+    def f(): return [][0]
+IndexError: list index out of range
+";
+        let exception = python_exception(stderr, &sources(&["program.py"])).unwrap();
+        assert_eq!(exception.exception_type, "IndexError");
+        assert_eq!(
+            exception.message.as_deref(),
+            Some("list index out of range")
+        );
+        assert_eq!(exception.file.as_deref(), Some("program.py"));
+        assert_eq!(exception.line, Some(2));
+    }
+
+    #[test]
+    fn finds_helper_modules_from_files() {
+        let stderr = "Traceback (most recent call last):
+  File \"/tmp/.tmpX/program.py\", line 5, in <module>
+    solve()
+  File \"/tmp/.tmpX/lib/helper.py\", line 8, in solve
+    return 1 // 0
+  File \"/usr/lib/python3.12/fractions.py\", line 1, in __floordiv__
+ZeroDivisionError: integer division or modulo by zero
+";
+        let source_files = sources(&["program.py", "lib/helper.py"]);
+        let exception = parse_exception(stderr, &source_files).unwrap();
+        assert_eq!(exception.exception_type, "ZeroDivisionError");
+        assert_eq!(exception.file.as_deref(), Some("lib/helper.py"));
+        assert_eq!(exception.line, Some(8));
+
+        assert_eq!(
+            find_source_file("/tmp/.tmpX/lib/helper.py", &source_files),
+            Some(&source_files[1])
+        );
+        assert_eq!(find_source_file("/tmp/.tmpX/other.py", &source_files), None);
+    }
+}
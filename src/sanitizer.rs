@@ -0,0 +1,287 @@
+//! Parses AddressSanitizer and UndefinedBehaviorSanitizer reports out of a program's stderr.
+//!
+//! When programs are compiled with `-fsanitize=address,undefined`, errors are reported like
+//!
+//! ```text
+//! program.cpp:15:8: runtime error: signed integer overflow: 2147483647 + 2147483647 cannot be represented in type 'int'
+//! ```
+//!
+//! which is turned into a [`RuntimeErrorDetails`] so that the IDE can show a short explanation and
+//! highlight the line.
+
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct StackFrame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RuntimeErrorDetails {
+    /// "AddressSanitizer", "UndefinedBehaviorSanitizer" or "LeakSanitizer".
+    pub sanitizer: String,
+    /// Short category, like "heap-buffer-overflow" or "signed-integer-overflow".
+    pub kind: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    /// The sanitizer's description of the error, like "signed integer overflow: 2147483647 +
+    /// 2147483647 cannot be represented in type 'int'".
+    pub message: String,
+    /// One-line summary for students, like "Line 15: Char 8: runtime error: signed integer
+    /// overflow: ... (program.cpp)".
+    pub summary: String,
+    /// Innermost frame first. Empty for UndefinedBehaviorSanitizer unless
+    /// `UBSAN_OPTIONS=print_stacktrace=1` is set.
+    pub stack: Vec<StackFrame>,
+}
+
+/// Sources are compiled in a temporary directory like /tmp/.tmpAbC123, which isn't meaningful to
+/// users, so it is removed from paths.
+fn clean_path(path: &str) -> String {
+    let re = Regex::new(r"^/tmp/\.tmp\w+/").unwrap();
+    re.replace(path, "").into_owned()
+}
+
+/// Splits "file:line:column" or "file:line" into its parts.
+fn parse_location(location: &str) -> (Option<String>, Option<u32>, Option<u32>) {
+    let re = Regex::new(r"^(?<file>[^:()]+):(?<line>\d+)(:(?<column>\d+))?$").unwrap();
+    match re.captures(location.trim()) {
+        Some(captures) => (
+            Some(clean_path(&captures["file"])),
+            captures["line"].parse().ok(),
+            captures
+                .name("column")
+                .and_then(|c| c.as_str().parse().ok()),
+        ),
+        None => (None, None, None),
+    }
+}
+
+fn parse_stack(report: &str) -> Vec<StackFrame> {
+    let frame_re =
+        Regex::new(r"^\s*#\d+ 0x[0-9a-f]+(?: in (?<function>\S+))? (?<location>.+)$").unwrap();
+    report
+        .lines()
+        .filter_map(|line| frame_re.captures(line))
+        .map(|captures| {
+            let (file, line, column) = parse_location(&captures["location"]);
+            StackFrame {
+                function: captures.name("function").map(|f| f.as_str().to_owned()),
+                file,
+                line,
+                column,
+            }
+        })
+        .collect()
+}
+
+fn ubsan_kind(message: &str) -> &'static str {
+    if message.starts_with("signed integer overflow") {
+        "signed-integer-overflow"
+    } else if message.starts_with("division by zero") {
+        "division-by-zero"
+    } else if message.starts_with("index ") && message.contains("out of bounds") {
+        "index-out-of-bounds"
+    } else if message.contains("null pointer") {
+        "null-pointer"
+    } else if message.starts_with("shift exponent") || message.starts_with("left shift") {
+        "shift-out-of-bounds"
+    } else if message.contains("misaligned address") {
+        "misaligned-access"
+    } else {
+        "undefined-behavior"
+    }
+}
+
+fn summarize(
+    file: &Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+    message: &str,
+) -> String {
+    let mut summary = String::new();
+    if let Some(line) = line {
+        summary += &format!("Line {line}: ");
+    }
+    if let Some(column) = column {
+        summary += &format!("Char {column}: ");
+    }
+    summary += &format!("runtime error: {message}");
+    if let Some(file) = file {
+        summary += &format!(" ({file})");
+    }
+    summary
+}
+
+/// Returns the first sanitizer error in `stderr`, if any.
+pub fn parse(stderr: &str) -> Option<RuntimeErrorDetails> {
+    let ubsan_re = Regex::new(r"(?m)^(?<location>\S+): runtime error: (?<message>.+)$").unwrap();
+    let asan_re =
+        Regex::new(r"(?m)^==\d+==ERROR: (?<sanitizer>\w+Sanitizer): (?<message>.+)$").unwrap();
+
+    let ubsan = ubsan_re.captures(stderr);
+    let asan = asan_re.captures(stderr);
+    // Report whichever error came first.
+    let use_ubsan = match (&ubsan, &asan) {
+        (Some(ubsan), Some(asan)) => ubsan.get(0).unwrap().start() < asan.get(0).unwrap().start(),
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => return None,
+    };
+
+    if use_ubsan {
+        let captures = ubsan.unwrap();
+        let (file, line, column) = parse_location(&captures["location"]);
+        let message = captures["message"].to_owned();
+        let rest = &stderr[captures.get(0).unwrap().end()..];
+        // Only the frames printed before the next report belong to this one.
+        let report_end = rest.find("runtime error:").unwrap_or(rest.len());
+        return Some(RuntimeErrorDetails {
+            sanitizer: "UndefinedBehaviorSanitizer".to_owned(),
+            kind: ubsan_kind(&message).to_owned(),
+            summary: summarize(&file, line, column, &message),
+            file,
+            line,
+            column,
+            message,
+            stack: parse_stack(&rest[..report_end]),
+        });
+    }
+
+    let captures = asan.unwrap();
+    let sanitizer = captures["sanitizer"].to_owned();
+    let message = captures["message"].trim().to_owned();
+    let kind = if sanitizer == "LeakSanitizer" {
+        "memory-leak".to_owned()
+    } else {
+        // "heap-buffer-overflow on address 0x602000000020 at pc ..." -> "heap-buffer-overflow"
+        message
+            .split_whitespace()
+            .next()
+            .unwrap_or("unknown")
+            .to_owned()
+    };
+    // Only keep the stack where the error happened, and not later ones such as where the memory
+    // was allocated. Stacks are separated by blank lines.
+    let report = &stderr[captures.get(0).unwrap().end()..];
+    let stack_start = report.find("    #0 ").unwrap_or(0);
+    let stack_end = report[stack_start..]
+        .find("\n\n")
+        .map_or(report.len(), |index| stack_start + index);
+    let stack = parse_stack(&report[..stack_end]);
+    // The innermost frame that is in the user's code, rather than a library.
+    let user_frame = stack.iter().find(|frame| {
+        frame
+            .file
+            .as_ref()
+            .is_some_and(|file| !file.starts_with('/') && frame.line.is_some())
+    });
+    let (file, line, column) = match user_frame {
+        Some(frame) => (frame.file.clone(), frame.line, frame.column),
+        None => (None, None, None),
+    };
+    let short_message = match message.find(" on address") {
+        Some(index) => message[..index].to_owned(),
+        None => message.clone(),
+    };
+
+    Some(RuntimeErrorDetails {
+        sanitizer,
+        kind,
+        summary: summarize(&file, line, column, &short_message),
+        file,
+        line,
+        column,
+        message,
+        stack,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ubsan_errors() {
+        let stderr = "\
+/tmp/.tmpAbC123/program.cpp:15:8: runtime error: signed integer overflow: 2147483647 + 1 cannot be represented in type 'int'
+";
+        let details = parse(stderr).unwrap();
+        assert_eq!(details.sanitizer, "UndefinedBehaviorSanitizer");
+        assert_eq!(details.kind, "signed-integer-overflow");
+        assert_eq!(details.file.as_deref(), Some("program.cpp"));
+        assert_eq!((details.line, details.column), (Some(15), Some(8)));
+        assert_eq!(
+            details.summary,
+            "Line 15: Char 8: runtime error: signed integer overflow: 2147483647 + 1 cannot be \
+             represented in type 'int' (program.cpp)"
+        );
+        assert!(details.stack.is_empty());
+    }
+
+    #[test]
+    fn parses_asan_errors_at_the_innermost_user_frame() {
+        let stderr = "\
+=================================================================
+==16661==ERROR: AddressSanitizer: heap-buffer-overflow on address 0x602000000018 at pc 0x557a89028256 bp 0x7ffdb2b28ec0 sp 0x7ffdb2b28eb8
+READ of size 4 at 0x602000000018 thread T0
+    #0 0x557a89028200 in std::vector<int>::operator[](unsigned long) /usr/include/c++/12/bits/stl_vector.h:1123
+    #1 0x557a89028255 in main /tmp/.tmpAbC123/program.cpp:4:11
+    #2 0x7f9a4e845249  (/lib/x86_64-linux-gnu/libc.so.6+0x27249)
+
+0x602000000018 is located 0 bytes to the right of 8-byte region [0x602000000010,0x602000000018)
+allocated by thread T0 here:
+    #0 0x7f9a4f6b9628 in operator new[](unsigned long) ../../../../src/libsanitizer/asan/asan_new_delete.cpp:98
+    #1 0x557a890281ca in main /tmp/.tmpAbC123/program.cpp:3
+
+SUMMARY: AddressSanitizer: heap-buffer-overflow /tmp/.tmpAbC123/program.cpp:4 in main
+";
+        let details = parse(stderr).unwrap();
+        assert_eq!(details.sanitizer, "AddressSanitizer");
+        assert_eq!(details.kind, "heap-buffer-overflow");
+        assert_eq!(details.file.as_deref(), Some("program.cpp"));
+        assert_eq!((details.line, details.column), (Some(4), Some(11)));
+        assert_eq!(
+            details.summary,
+            "Line 4: Char 11: runtime error: heap-buffer-overflow (program.cpp)"
+        );
+        // The stack where the memory was allocated isn't included.
+        assert_eq!(details.stack.len(), 3);
+        assert_eq!(details.stack[1].function.as_deref(), Some("main"));
+        assert_eq!(details.stack[2].file, None);
+    }
+
+    #[test]
+    fn parses_leaks() {
+        let stderr = "\
+==123==ERROR: LeakSanitizer: detected memory leaks
+
+Direct leak of 4 byte(s) in 1 object(s) allocated from:
+    #0 0x7f9a4f6b9628 in operator new(unsigned long) ../../../../src/libsanitizer/asan/asan_new_delete.cpp:95
+    #1 0x557a890281ca in main /tmp/.tmpAbC123/program.cpp:3
+";
+        let details = parse(stderr).unwrap();
+        assert_eq!(details.sanitizer, "LeakSanitizer");
+        assert_eq!(details.kind, "memory-leak");
+        assert_eq!(details.message, "detected memory leaks");
+    }
+
+    #[test]
+    fn reports_the_first_error() {
+        let stderr = "\
+program.cpp:3:5: runtime error: division by zero
+==1==ERROR: AddressSanitizer: SEGV on unknown address 0x000000000000
+";
+        assert_eq!(parse(stderr).unwrap().kind, "division-by-zero");
+    }
+
+    #[test]
+    fn ignores_output_without_sanitizer_errors() {
+        assert!(parse("terminate called after throwing an instance of 'int'\n").is_none());
+    }
+}
@@ -1,24 +1,15 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub enum Language {
-    #[serde(rename = "cpp")]
-    Cpp,
-    #[serde(rename = "java21")]
-    Java21,
-    #[serde(rename = "py12")]
-    Py12,
-}
-
-// for the future, when implementing grader / scorer support, we probably want to add an "additional_files" field to executable.
-// grader: https://probgate.org/viewsolution.php?grader_id=557
-// needs 3 files, input, output, answer
-// scorer: https://probgate.org/viewsolution.php?scorer_id=4
-// needs N files, one for each test case
+/// A language id such as "cpp", "java21", or "py12". Supported languages are listed in
+/// `languages.json`; see [`crate::languages`].
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(transparent)]
+pub struct Language(pub String);
 
-#[derive(Serialize, Deserialize)]
-pub struct Executable {
-    /// base64 .tar.gz file
-    pub files: String,
-    pub run_command: String,
+/// A file submitted along with the main source, like a header or helper module.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SourceFile {
+    /// Relative path such as "utils.h" or "com/example/Helper.java".
+    pub path: String,
+    pub content: String,
 }